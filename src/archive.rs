@@ -0,0 +1,275 @@
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Archived versions are named like `notes.txt~20161203-142501`, with the time the sync started in
+// UTC. If a file is archived more than once in the same second, the later versions are numbered,
+// like `notes.txt~20161203-142501.1`.
+const VERSION_SEPARATOR: char = '~';
+const NUMBER_SEPARATOR: char = '.';
+const TIME_LEN: usize = 15;
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Returns the name that a version of the file or directory named `name` archived at `time` has.
+pub fn version_name(name: &OsStr, time: SystemTime) -> OsString {
+    let mut version_name = name.to_os_string();
+    version_name.push(format!("{}{}", VERSION_SEPARATOR, format_time(time)));
+    version_name
+}
+
+/// If `version_name` is the name of an archived version, returns the name of the original file, the
+/// time it was archived, and the number of versions archived before it in the same second.
+pub fn parse_version_name(version_name: &OsStr) -> Option<(&str, SystemTime, u32)> {
+    let version_name = match version_name.to_str() {
+        Some(version_name) => version_name,
+        None => return None,
+    };
+    let sep_index = match version_name.rfind(VERSION_SEPARATOR) {
+        Some(sep_index) if sep_index > 0 => sep_index,
+        _ => return None,
+    };
+    let version = &version_name[sep_index + 1..];
+    let (time, number) = match version.find(NUMBER_SEPARATOR) {
+        Some(number_index) => {
+            let number = &version[number_index + 1..];
+            if number.is_empty() || !number.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            (&version[..number_index], number.parse().ok()?)
+        },
+        None => (version, 0),
+    };
+    parse_time(time).map(|time| (&version_name[..sep_index], time, number))
+}
+
+/// Moves a deleted file or directory into the archive. If it can't be renamed, as when the archive
+/// is on a different drive, it is copied and then deleted. If there is already a version at
+/// `archive_path`, a number is added to the name. Returns the path it was moved to.
+pub fn move_to_archive(path: &Path, archive_path: &Path) -> io::Result<PathBuf> {
+    if let Some(parent) = archive_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let archive_path = unused_path(archive_path);
+    if fs::rename(path, &archive_path).is_ok() {
+        return Ok(archive_path);
+    }
+    let meta = fs::symlink_metadata(path)?;
+    if meta.is_dir() {
+        copy_dir_all(path, &archive_path)?;
+        fs::remove_dir_all(path)?;
+    } else if meta.file_type().is_symlink() {
+        copy_symlink(path, &archive_path)?;
+        fs::remove_file(path)?;
+    } else {
+        fs::copy(path, &archive_path)?;
+        fs::remove_file(path)?;
+    }
+    Ok(archive_path)
+}
+
+/// Puts a copy of a file that is about to be replaced into the archive, leaving the file where it
+/// is. A hard link is used if possible so that nothing has to be copied. Like `move_to_archive()`,
+/// it returns the path of the copy.
+pub fn copy_to_archive(path: &Path, archive_path: &Path) -> io::Result<PathBuf> {
+    if let Some(parent) = archive_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let archive_path = unused_path(archive_path);
+    if fs::hard_link(path, &archive_path).is_err() {
+        fs::copy(path, &archive_path)?;
+    }
+    Ok(archive_path)
+}
+
+// Returns `archive_path`, or if something is already there, the path with the first number that
+// isn't used added to it.
+fn unused_path(archive_path: &Path) -> PathBuf {
+    let mut path = archive_path.to_path_buf();
+    let mut number = 0;
+    while fs::symlink_metadata(&path).is_ok() {
+        number += 1;
+        let mut numbered = archive_path.as_os_str().to_os_string();
+        numbered.push(format!("{}{}", NUMBER_SEPARATOR, number));
+        path = PathBuf::from(numbered);
+    }
+    path
+}
+
+// Symlinks are copied as symlinks instead of copying what they point to.
+fn copy_dir_all(src: &Path, dest: &Path) -> io::Result<()> {
+    fs::create_dir(dest)?;
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let dest_path = dest.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            copy_dir_all(&entry.path(), &dest_path)?;
+        } else if file_type.is_symlink() {
+            copy_symlink(&entry.path(), &dest_path)?;
+        } else {
+            fs::copy(entry.path(), dest_path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn copy_symlink(src: &Path, dest: &Path) -> io::Result<()> {
+    ::std::os::unix::fs::symlink(fs::read_link(src)?, dest)
+}
+
+#[cfg(windows)]
+fn copy_symlink(src: &Path, dest: &Path) -> io::Result<()> {
+    let target = fs::read_link(src)?;
+    if fs::metadata(src).map(|meta| meta.is_dir()).unwrap_or(false) {
+        ::std::os::windows::fs::symlink_dir(target, dest)
+    } else {
+        ::std::os::windows::fs::symlink_file(target, dest)
+    }
+}
+
+// Formats the time as `YYYYMMDD-HHMMSS` in UTC. Times before 1970 are formatted as 1970.
+fn format_time(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map(|dur| dur.as_secs()).unwrap_or(0);
+    let (year, month, day) = civil_from_days(secs / SECS_PER_DAY);
+    let secs_of_day = secs % SECS_PER_DAY;
+    format!("{:04}{:02}{:02}-{:02}{:02}{:02}", year, month, day,
+            secs_of_day / 3600, secs_of_day / 60 % 60, secs_of_day % 60)
+}
+
+fn parse_time(s: &str) -> Option<SystemTime> {
+    if s.len() != TIME_LEN || s.as_bytes()[8] != b'-' {
+        return None;
+    }
+    let field = |range: ::std::ops::Range<usize>| -> Option<u64> {
+        let digits = &s[range];
+        if digits.bytes().all(|b| b.is_ascii_digit()) { digits.parse().ok() } else { None }
+    };
+    let (year, month, day) = (field(0..4)?, field(4..6)?, field(6..8)?);
+    let (hour, minute, second) = (field(9..11)?, field(11..13)?, field(13..15)?);
+    if year < 1970 || month < 1 || month > 12 || day < 1 || day > 31 ||
+       hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    let secs = days_from_civil(year, month, day) * SECS_PER_DAY +
+               hour * 3600 + minute * 60 + second;
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+// These convert between days since 1970-01-01 and a date in the Gregorian calendar, using the
+// algorithms from http://howardhinnant.github.io/date_algorithms.html limited to dates after 1970.
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let yoe = year - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::ffi::OsStr;
+    use std::fs;
+    use std::io::Write;
+    use std::time::{Duration, UNIX_EPOCH};
+    use super::{copy_to_archive, move_to_archive, parse_version_name, version_name};
+
+    #[test]
+    fn test_version_name() {
+        let time = UNIX_EPOCH + Duration::from_secs(1_480_775_101);
+        let name = version_name(OsStr::new("notes.txt"), time);
+        assert_eq!(name, OsStr::new("notes.txt~20161203-142501"));
+        assert_eq!(parse_version_name(&name), Some(("notes.txt", time, 0)));
+        assert_eq!(parse_version_name(OsStr::new("notes.txt~20161203-142501.12")),
+                   Some(("notes.txt", time, 12)));
+
+        let time = UNIX_EPOCH + Duration::from_secs(951_782_400);
+        let name = version_name(OsStr::new("a~b"), time);
+        assert_eq!(name, OsStr::new("a~b~20000229-000000"));
+        assert_eq!(parse_version_name(&name), Some(("a~b", time, 0)));
+
+        assert_eq!(parse_version_name(OsStr::new("notes.txt")), None);
+        assert_eq!(parse_version_name(OsStr::new("~20161203-142501")), None);
+        assert_eq!(parse_version_name(OsStr::new("a~20161303-142501")), None);
+        assert_eq!(parse_version_name(OsStr::new("a~2016120-3142501")), None);
+        assert_eq!(parse_version_name(OsStr::new("a~20161203-142501.")), None);
+        assert_eq!(parse_version_name(OsStr::new("a~20161203-142501.x")), None);
+    }
+
+    #[test]
+    fn test_same_second_versions() {
+        let dir = env::temp_dir().join("ArchiveSameSecondTests");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).expect("failed to create ArchiveSameSecondTests");
+        let path = dir.join("a.txt");
+        let archive_path = dir.join("archive").join(version_name(OsStr::new("a.txt"), UNIX_EPOCH));
+
+        // Each version gets its own name instead of replacing the one before it.
+        let mut archived = vec![];
+        for contents in &["1", "2", "3"] {
+            let mut file = fs::File::create(&path).expect("failed to create a.txt");
+            file.write_all(contents.as_bytes()).expect("failed to write a.txt");
+            drop(file);
+            if *contents == "3" {
+                archived.push(move_to_archive(&path, &archive_path).expect("failed to archive"));
+            } else {
+                archived.push(copy_to_archive(&path, &archive_path).expect("failed to archive"));
+                // Replace the file instead of changing the hard linked one.
+                fs::remove_file(&path).expect("failed to delete a.txt");
+            }
+        }
+        let names: Vec<_> = archived.iter().map(|path| path.file_name().unwrap()).collect();
+        assert_eq!(names, &["a.txt~19700101-000000", "a.txt~19700101-000000.1",
+                            "a.txt~19700101-000000.2"]);
+        let contents: Vec<_> = archived.iter().map(|path| {
+            fs::read_to_string(path).expect("failed to read archived a.txt")
+        }).collect();
+        assert_eq!(contents, &["1", "2", "3"]);
+        assert!(fs::symlink_metadata(&path).is_err());
+
+        fs::remove_dir_all(&dir).expect("failed to delete ArchiveSameSecondTests");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_copy_dir_symlinks() {
+        let dir = env::temp_dir().join("ArchiveSymlinkTests");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("src/sub")).expect("failed to create ArchiveSymlinkTests");
+        fs::write(dir.join("target.txt"), b"t").expect("failed to create target.txt");
+        ::std::os::unix::fs::symlink("../target.txt", dir.join("src/link"))
+            .expect("failed to create link");
+        ::std::os::unix::fs::symlink("..", dir.join("src/sub/loop"))
+            .expect("failed to create loop");
+
+        super::copy_dir_all(&dir.join("src"), &dir.join("copy")).expect("failed to copy");
+        let link_meta = fs::symlink_metadata(dir.join("copy/link")).expect("failed to read link");
+        assert!(link_meta.file_type().is_symlink());
+        assert_eq!(fs::read_link(dir.join("copy/link")).expect("failed to read link").to_str(),
+                   Some("../target.txt"));
+        let loop_meta = fs::symlink_metadata(dir.join("copy/sub/loop"))
+                            .expect("failed to read loop");
+        assert!(loop_meta.file_type().is_symlink());
+
+        fs::remove_dir_all(&dir).expect("failed to delete ArchiveSymlinkTests");
+    }
+}
@@ -0,0 +1,306 @@
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
+
+use mirror_sync::filter::{FileKind, MetadataFilter};
+use mirror_sync::jobs::JobStore;
+use mirror_sync::sync::{PlannedOperation, SymlinkPolicy, SyncBuilder, SyncLogEntry, SyncLogLevel,
+                        SyncOperation};
+
+// Exit codes
+const EXIT_SUCCESS: i32 = 0;
+const EXIT_SYNC_ERRORS: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+
+const USAGE: &'static str = "\
+Usage:
+    mirror-sync                         Open the main window
+    mirror-sync list                    List the saved jobs
+    mirror-sync run JOB [OPTIONS]       Run the saved job named JOB
+    mirror-sync sync SRC DEST [OPTIONS] Mirror the directory SRC to DEST
+
+Options:
+    --parallel N        Number of files to copy at once
+    --date              Copy a file if its modified date is different
+    --no-size           Don't copy a file just because its size is different
+    --start-end BYTES   Copy a file if its first or last BYTES bytes are different (0 to disable)
+    --contents          Copy a file if any of its contents are different
+    --no-created-date   Don't copy the created date of copied files
+    --no-modified-date  Don't copy the modified date of copied files
+    --permissions       Copy the permissions of files and directories
+    --ownership         Copy the owner and group of files and directories when running as root
+    --xattrs            Copy the extended attributes and ACLs of files and directories (Linux only)
+    --hard-links        Hard link files in the destination that are hard linked in the source
+    --gitignore         Exclude files listed in .gitignore files as well as .mirrorignore files
+    --min-size BYTES    Exclude files smaller than BYTES
+    --max-size BYTES    Exclude files larger than BYTES
+    --max-age DAYS      Exclude files last modified more than DAYS days ago
+    --min-age DAYS      Exclude files last modified less than DAYS days ago
+    --no-hidden         Exclude hidden files and directories
+    --exclude-type TYPE Exclude files of TYPE (file, dir, symlink, or other)
+    --symlinks POLICY   Copy symlinks as symlinks, follow them, or skip them (copy, follow, or
+                        skip)
+    --archive DIR       Move deleted and replaced files into DIR instead of destroying them
+    --keep-versions N   Keep only the newest N versions of each file in the archive
+    --keep-days N       Delete versions archived more than N days ago
+    --max-delete N      Don't delete anything if more than N files and directories would be deleted
+    --max-delete-percent PERCENT
+                        Don't delete anything if more than PERCENT of the destination would be
+                        deleted
+    --allow-empty-source
+                        Delete from the destination even if a source directory is empty or missing
+    --refuse-empty-source
+                        Don't delete anything if a source directory is empty or missing
+    --marker NAME       Only sync directories that contain a file named NAME
+    --dry-run           Print what would be done without changing the destination
+    -v, --verbose       Print debug messages
+
+Exit status is 0 on success, 1 if any errors occurred while syncing, and 2 if the command line or
+settings are invalid.";
+
+struct Flags {
+    dry_run: bool,
+    verbose: bool,
+}
+
+/// Runs the command line interface with the arguments after the program name and returns the exit
+/// code for the process.
+pub fn run(args: &[String]) -> i32 {
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        println!("{}", USAGE);
+        return EXIT_SUCCESS;
+    }
+    let command = match args.first() {
+        Some(command) => command.as_str(),
+        None => return usage_error("no command given"),
+    };
+    match command {
+        "list" if args.len() == 1 => list_jobs(),
+        "run" if args.len() >= 2 => run_job(&args[1], &args[2..]),
+        "sync" if args.len() >= 3 => {
+            let mut builder = SyncBuilder::new();
+            builder.add_directory_pair(PathBuf::from(&args[1]), PathBuf::from(&args[2]));
+            match parse_options(&args[3..], &mut builder, MetadataFilter::new()) {
+                Ok(flags) => execute(&mut builder, &flags),
+                Err(err) => usage_error(&err),
+            }
+        },
+        _ => usage_error("invalid command"),
+    }
+}
+
+fn usage_error(message: &str) -> i32 {
+    eprintln!("error: {}\n\n{}", message, USAGE);
+    EXIT_USAGE
+}
+
+fn list_jobs() -> i32 {
+    let saved_jobs = match JobStore::user_default().and_then(|store| store.load()) {
+        Ok(saved_jobs) => saved_jobs,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            return EXIT_USAGE;
+        },
+    };
+    for job in &saved_jobs {
+        println!("{}", job.name);
+        for &(ref src, ref dest) in &job.directories {
+            println!("    {} -> {}", src.to_string_lossy(), dest.to_string_lossy());
+        }
+    }
+    EXIT_SUCCESS
+}
+
+fn run_job(name: &str, options: &[String]) -> i32 {
+    let saved_jobs = match JobStore::user_default().and_then(|store| store.load()) {
+        Ok(saved_jobs) => saved_jobs,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            return EXIT_USAGE;
+        },
+    };
+    let job = match saved_jobs.iter().find(|job| job.name == name) {
+        Some(job) => job,
+        None => {
+            eprintln!("error: there is no job named \"{}\"", name);
+            return EXIT_USAGE;
+        },
+    };
+    let mut builder = job.sync_builder();
+    match parse_options(options, &mut builder, job.metadata_filter.clone()) {
+        Ok(flags) => execute(&mut builder, &flags),
+        Err(err) => usage_error(&err),
+    }
+}
+
+// The metadata filter options are added to `metadata_filter`, which should be the one already set
+// on the builder.
+fn parse_options(options: &[String], builder: &mut SyncBuilder, mut metadata_filter: MetadataFilter)
+                 -> Result<Flags, String> {
+    let mut flags = Flags {
+        dry_run: false,
+        verbose: false,
+    };
+    let mut iter = options.iter();
+    while let Some(option) = iter.next() {
+        match option.as_str() {
+            "--parallel" => {
+                let value = iter.next().and_then(|value| value.parse::<u8>().ok())
+                                .filter(|&value| value > 0)
+                                .ok_or("--parallel needs a number from 1 to 255")?;
+                builder.parallel_copies(value);
+            },
+            "--date" => { builder.copy_contents_if_date_mismatched(true); },
+            "--no-size" => { builder.copy_contents_if_size_mismatched(false); },
+            "--start-end" => {
+                let value = iter.next().and_then(|value| value.parse::<u32>().ok())
+                                .ok_or("--start-end needs a number of bytes")?;
+                builder.copy_contents_if_start_end_mismatched_size(value);
+            },
+            "--contents" => { builder.copy_contents_if_contents_mismatched(true); },
+            "--no-created-date" => { builder.copy_created_date(false); },
+            "--no-modified-date" => { builder.copy_modified_date(false); },
+            "--permissions" => { builder.copy_permissions(true); },
+            "--ownership" => { builder.copy_ownership(true); },
+            "--xattrs" => { builder.copy_xattrs(true); },
+            "--hard-links" => { builder.preserve_hard_links(true); },
+            "--gitignore" => { builder.filter_file_names(&[".mirrorignore", ".gitignore"]); },
+            "--min-size" => {
+                let value = iter.next().and_then(|value| value.parse::<u64>().ok())
+                                .ok_or("--min-size needs a number of bytes")?;
+                metadata_filter.min_size = Some(value);
+            },
+            "--max-size" => {
+                let value = iter.next().and_then(|value| value.parse::<u64>().ok())
+                                .ok_or("--max-size needs a number of bytes")?;
+                metadata_filter.max_size = Some(value);
+            },
+            "--max-age" => {
+                let value = iter.next().and_then(|value| value.parse::<u64>().ok())
+                                .ok_or("--max-age needs a number of days")?;
+                let time = days_ago(value).ok_or("--max-age is too many days")?;
+                metadata_filter.modified_after = Some(time);
+            },
+            "--min-age" => {
+                let value = iter.next().and_then(|value| value.parse::<u64>().ok())
+                                .ok_or("--min-age needs a number of days")?;
+                let time = days_ago(value).ok_or("--min-age is too many days")?;
+                metadata_filter.modified_before = Some(time);
+            },
+            "--no-hidden" => metadata_filter.exclude_hidden = true,
+            "--exclude-type" => {
+                let value = iter.next().ok_or("--exclude-type needs a file type")?;
+                metadata_filter.exclude_kinds.push(value.parse::<FileKind>()?);
+            },
+            "--symlinks" => {
+                let value = iter.next().ok_or("--symlinks needs a policy")?;
+                builder.symlink_policy(value.parse::<SymlinkPolicy>()?);
+            },
+            "--archive" => {
+                let value = iter.next().ok_or("--archive needs a directory")?;
+                builder.archive_dir(PathBuf::from(value));
+            },
+            "--keep-versions" => {
+                let value = iter.next().and_then(|value| value.parse::<u32>().ok())
+                                .ok_or("--keep-versions needs a number of versions")?;
+                builder.archive_keep_versions(value);
+            },
+            "--keep-days" => {
+                let value = iter.next().and_then(|value| value.parse::<u32>().ok())
+                                .ok_or("--keep-days needs a number of days")?;
+                builder.archive_keep_days(value);
+            },
+            "--max-delete" => {
+                let value = iter.next().and_then(|value| value.parse::<u64>().ok())
+                                .ok_or("--max-delete needs a number of files")?;
+                builder.max_deleted_entries(value);
+            },
+            "--max-delete-percent" => {
+                let value = iter.next().and_then(|value| value.parse::<u8>().ok())
+                                .filter(|&value| value <= 100)
+                                .ok_or("--max-delete-percent needs a number from 0 to 100")?;
+                builder.max_deleted_percent(value);
+            },
+            "--allow-empty-source" => { builder.refuse_empty_source(false); },
+            "--refuse-empty-source" => { builder.refuse_empty_source(true); },
+            "--marker" => {
+                let value = iter.next().ok_or("--marker needs a file name")?;
+                builder.marker_file(value.as_str());
+            },
+            "--dry-run" => flags.dry_run = true,
+            "-v" | "--verbose" => flags.verbose = true,
+            _ => return Err(format!("unknown option {}", option)),
+        }
+    }
+    builder.metadata_filter(metadata_filter);
+    Ok(flags)
+}
+
+// Returns `None` if the time would be too far in the past to represent.
+fn days_ago(days: u64) -> Option<SystemTime> {
+    let secs = days.checked_mul(24 * 60 * 60)?;
+    SystemTime::now().checked_sub(Duration::from_secs(secs))
+}
+
+fn execute(builder: &mut SyncBuilder, flags: &Flags) -> i32 {
+    let start = Instant::now();
+    let op = if flags.dry_run { builder.plan() } else { builder.sync() };
+    // The receiver is closed when the operation is done.
+    for entry in op.log_receiver() {
+        print_log_entry(start, &entry, flags.verbose);
+        print_plan(&op);
+    }
+    print_plan(&op);
+
+    let report = op.wait();
+    if report.pairs.len() > 1 {
+        for pair in &report.pairs {
+            println!("{} -> {}: copied {} files ({} bytes), deleted {} files and {} directories, \
+                      {} errors",
+                     pair.src.to_string_lossy(), pair.dest.to_string_lossy(), pair.files_copied,
+                     pair.bytes_copied, pair.files_deleted, pair.dirs_deleted, pair.errors);
+        }
+    }
+    println!("Copied {} files ({} bytes), deleted {} files and {} directories, archived {}, \
+              {} errors in {:.1}s ({:.0} bytes/s)",
+             report.files_copied, report.bytes_copied, report.files_deleted,
+             report.dirs_deleted, report.files_archived, report.errors,
+             report.duration.as_secs_f32(), report.bytes_per_sec);
+    if report.sparse_bytes_skipped > 0 {
+        println!("Skipped {} bytes in the holes of sparse files", report.sparse_bytes_skipped);
+    }
+    if report.errors > 0 { EXIT_SYNC_ERRORS } else { EXIT_SUCCESS }
+}
+
+fn print_log_entry(start: Instant, entry: &SyncLogEntry, verbose: bool) {
+    let secs = entry.time.duration_since(start).as_secs_f32();
+    match entry.level {
+        SyncLogLevel::Error => eprintln!("{:.3}s {:?}: {}", secs, entry.level, entry.message),
+        SyncLogLevel::Debug if !verbose => {},
+        _ => println!("{:.3}s {:?}: {}", secs, entry.level, entry.message),
+    }
+}
+
+fn print_plan(op: &SyncOperation) {
+    while let Some(planned) = op.read_plan() {
+        match planned {
+            PlannedOperation::CreateDir(dir) =>
+                println!("Would create directory {}", dir.to_string_lossy()),
+            PlannedOperation::CopyFile { src, dest, reason } =>
+                println!("Would copy {} to {} ({:?})",
+                         src.to_string_lossy(), dest.to_string_lossy(), reason),
+            PlannedOperation::CopySymlink { dest, target, .. } =>
+                println!("Would create symlink {} to {}",
+                         dest.to_string_lossy(), target.to_string_lossy()),
+            PlannedOperation::HardLink { dest, target, .. } =>
+                println!("Would link {} to {}", dest.to_string_lossy(), target.to_string_lossy()),
+            PlannedOperation::SetPermissions(path) =>
+                println!("Would set permissions of {}", path.to_string_lossy()),
+            PlannedOperation::SetXattrs(path) =>
+                println!("Would set extended attributes of {}", path.to_string_lossy()),
+            PlannedOperation::DeleteFile(file) =>
+                println!("Would delete file {}", file.to_string_lossy()),
+            PlannedOperation::DeleteDirAll(dir) =>
+                println!("Would delete directory {}", dir.to_string_lossy()),
+        }
+    }
+}
@@ -0,0 +1,668 @@
+use std::fmt;
+use std::fs::{FileType, Metadata};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::time::SystemTime;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuleKind {
+    Include,
+    Exclude,
+    /// Keeps matching files and directories in the destination from being deleted, even if they
+    /// aren't in the source or are excluded. Protect rules don't affect what is copied.
+    Protect,
+}
+
+/// An include or exclude rule with a gitignore style pattern.
+///
+/// - `*` matches anything except `/`, `?` matches any one character except `/`, and `[abc]`
+///   matches one of the characters in the brackets. A `\` makes the next character match itself.
+/// - `**` as a whole path component matches any number of directories.
+/// - A pattern containing a `/` (other than at the end) is anchored and matches the path relative
+///   to the source directory. Any other pattern floats and matches the name of a file or
+///   directory at any depth.
+/// - A pattern ending in `/` only matches directories.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilterRule {
+    kind: RuleKind,
+    pattern: String,
+    anchored: bool,
+    dir_only: bool,
+    segments: Vec<Vec<char>>,
+}
+
+impl FilterRule {
+    pub fn new(kind: RuleKind, pattern: &str) -> Self {
+        let dir_only = pattern.ends_with('/');
+        let trimmed = pattern.trim_end_matches('/');
+        let anchored = trimmed.contains('/');
+        let segments = trimmed.split('/')
+                              .filter(|segment| !segment.is_empty())
+                              .map(|segment| segment.chars().collect())
+                              .collect();
+        FilterRule {
+            kind: kind,
+            pattern: pattern.into(),
+            anchored: anchored,
+            dir_only: dir_only,
+            segments: segments,
+        }
+    }
+
+    pub fn include(pattern: &str) -> Self {
+        Self::new(RuleKind::Include, pattern)
+    }
+
+    pub fn exclude(pattern: &str) -> Self {
+        Self::new(RuleKind::Exclude, pattern)
+    }
+
+    pub fn protect(pattern: &str) -> Self {
+        Self::new(RuleKind::Protect, pattern)
+    }
+
+    pub fn kind(&self) -> RuleKind {
+        self.kind
+    }
+
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    /// Returns true if the pattern matches the path, which is relative to the source directory.
+    pub fn matches(&self, path: &Path, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        let components: Vec<String> = path.components().filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_string_lossy().into_owned()),
+            _ => None,
+        }).collect();
+        let components: Vec<&str> = components.iter().map(|c| c.as_str()).collect();
+        if self.anchored {
+            match_segments(&self.segments, &components)
+        } else {
+            components.last().map_or(false, |name| match_segments(&self.segments, &[name]))
+        }
+    }
+}
+
+/// Formats the rule as `+ pattern`, `- pattern`, or `P pattern`, which `from_str()` parses.
+impl fmt::Display for FilterRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            RuleKind::Include => write!(f, "+ {}", self.pattern),
+            RuleKind::Exclude => write!(f, "- {}", self.pattern),
+            RuleKind::Protect => write!(f, "P {}", self.pattern),
+        }
+    }
+}
+
+/// Parses `+ pattern` as an include rule, `- pattern` as an exclude rule, and `P pattern` as a
+/// protect rule. Like a gitignore file, `!pattern` is an include rule and a pattern by itself is an
+/// exclude rule.
+impl FromStr for FilterRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (kind, pattern) = if s.starts_with("+ ") {
+            (RuleKind::Include, s[2..].trim_start())
+        } else if s.starts_with("- ") {
+            (RuleKind::Exclude, s[2..].trim_start())
+        } else if s.starts_with("P ") {
+            (RuleKind::Protect, s[2..].trim_start())
+        } else if s.starts_with('!') {
+            (RuleKind::Include, &s[1..])
+        } else {
+            (RuleKind::Exclude, s)
+        };
+        if pattern.trim_matches('/').is_empty() {
+            return Err(format!("filter rule \"{}\" has no pattern", s));
+        }
+        Ok(FilterRule::new(kind, pattern))
+    }
+}
+
+/// An ordered list of include, exclude, and protect rules. The last include or exclude rule that
+/// matches a path decides whether it is included. A path that no rule matches is included. A path
+/// that any protect rule matches is protected.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FilterRules {
+    rules: Vec<FilterRule>,
+}
+
+impl FilterRules {
+    pub fn new() -> Self {
+        FilterRules { rules: vec![] }
+    }
+
+    pub fn push(&mut self, rule: FilterRule) -> &mut Self {
+        self.rules.push(rule);
+        self
+    }
+
+    pub fn remove(&mut self, index: usize) -> FilterRule {
+        self.rules.remove(index)
+    }
+
+    pub fn rules(&self) -> &[FilterRule] {
+        &self.rules
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Parses the contents of a filter file like `.mirrorignore`, with one rule per line in the
+    /// format `FilterRule::from_str()` parses. Blank lines and lines starting with `#` are ignored.
+    pub fn from_filter_file(text: &str) -> Self {
+        let rules = text.lines()
+                        .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+                        .filter_map(|line| line.parse().ok())
+                        .collect();
+        FilterRules { rules: rules }
+    }
+
+    /// Parses the contents of a `.gitignore` file. Each line is a pattern that excludes what it
+    /// matches, or includes it if the pattern starts with `!`. Unlike `from_filter_file()`, there
+    /// are no `+`, `-`, or `P` prefixes, and spaces at the start of a line are part of the
+    /// pattern. Spaces at the end are ignored unless the last one is escaped with `\`, and `\#`
+    /// and `\!` at the start match `#` and `!`.
+    pub fn from_gitignore(text: &str) -> Self {
+        let rules = text.lines().filter_map(|line| {
+            if line.starts_with('#') {
+                return None;
+            }
+            let line = trim_trailing_spaces(line);
+            // The escapes are left in the pattern because a `\` makes the next character match
+            // itself.
+            let (kind, pattern) = if line.starts_with('!') {
+                (RuleKind::Include, &line[1..])
+            } else {
+                (RuleKind::Exclude, line)
+            };
+            if pattern.trim_matches('/').is_empty() {
+                None
+            } else {
+                Some(FilterRule::new(kind, pattern))
+            }
+        }).collect();
+        FilterRules { rules: rules }
+    }
+
+    /// Returns the last include or exclude rule that matches the path, which is relative to the
+    /// source directory.
+    pub fn matching_rule(&self, path: &Path, is_dir: bool) -> Option<&FilterRule> {
+        self.rules.iter().rev()
+                  .filter(|rule| rule.kind != RuleKind::Protect)
+                  .find(|rule| rule.matches(path, is_dir))
+    }
+
+    /// Returns true if a protect rule matches the path, which is relative to the destination
+    /// directory.
+    pub fn is_protected(&self, path: &Path, is_dir: bool) -> bool {
+        self.rules.iter().any(|rule| rule.kind == RuleKind::Protect && rule.matches(path, is_dir))
+    }
+
+    /// Returns true if the path, which is relative to the source directory, should be synced.
+    pub fn is_included(&self, path: &Path, is_dir: bool) -> bool {
+        self.matching_rule(path, is_dir).map_or(true, |rule| rule.kind == RuleKind::Include)
+    }
+
+    /// Returns a function that can be passed to `SyncBuilder::filter()`. Paths are matched relative
+    /// to whichever of the source directories they are in.
+    pub fn to_filter(&self, src_dirs: Vec<PathBuf>)
+                     -> impl Fn(&Path, &Metadata) -> bool + Send + Sync + 'static {
+        let rules = self.clone();
+        move |path: &Path, meta: &Metadata| {
+            // Use the innermost source directory in case one is inside another.
+            let rel_path = src_dirs.iter()
+                                   .filter_map(|src_dir| path.strip_prefix(src_dir).ok())
+                                   .min_by_key(|rel_path| rel_path.components().count());
+            match rel_path {
+                Some(rel_path) => rules.is_included(rel_path, meta.is_dir()),
+                None => true,
+            }
+        }
+    }
+
+    /// Returns a function that can be passed to `SyncBuilder::protect()`. Paths are matched
+    /// relative to whichever of the destination directories they are in.
+    pub fn to_protect_filter(&self, dest_dirs: Vec<PathBuf>)
+                             -> impl Fn(&Path, &Metadata) -> bool + Send + Sync + 'static {
+        let rules = self.clone();
+        move |path: &Path, meta: &Metadata| {
+            let rel_path = dest_dirs.iter()
+                                    .filter_map(|dest_dir| path.strip_prefix(dest_dir).ok())
+                                    .min_by_key(|rel_path| rel_path.components().count());
+            rel_path.map_or(false, |rel_path| rules.is_protected(rel_path, meta.is_dir()))
+        }
+    }
+}
+
+// Removes the spaces at the end of a line of a `.gitignore` file that aren't escaped.
+fn trim_trailing_spaces(line: &str) -> &str {
+    let mut end = line.len();
+    while line[..end].ends_with(' ') {
+        // An odd number of backslashes before the space means that the space is escaped.
+        let backslashes = line[..end - 1].chars().rev().take_while(|&c| c == '\\').count();
+        if backslashes % 2 == 1 {
+            break;
+        }
+        end -= 1;
+    }
+    &line[..end]
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    File,
+    Dir,
+    Symlink,
+    Other,
+}
+
+impl FileKind {
+    pub fn from_file_type(file_type: FileType) -> Self {
+        if file_type.is_file() {
+            FileKind::File
+        } else if file_type.is_dir() {
+            FileKind::Dir
+        } else if file_type.is_symlink() {
+            FileKind::Symlink
+        } else {
+            FileKind::Other
+        }
+    }
+}
+
+impl fmt::Display for FileKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            FileKind::File => "file",
+            FileKind::Dir => "dir",
+            FileKind::Symlink => "symlink",
+            FileKind::Other => "other",
+        })
+    }
+}
+
+impl FromStr for FileKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "file" => Ok(FileKind::File),
+            "dir" => Ok(FileKind::Dir),
+            "symlink" => Ok(FileKind::Symlink),
+            "other" => Ok(FileKind::Other),
+            _ => Err(format!("unknown file type \"{}\"", s)),
+        }
+    }
+}
+
+/// Excludes files based on their metadata instead of their path. The size and modified date limits
+/// only apply to files, so that a directory is never excluded because of its size or date.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MetadataFilter {
+    /// Files smaller than this many bytes are excluded.
+    pub min_size: Option<u64>,
+    /// Files larger than this many bytes are excluded.
+    pub max_size: Option<u64>,
+    /// Files last modified before this time are excluded.
+    pub modified_after: Option<SystemTime>,
+    /// Files last modified at or after this time are excluded.
+    pub modified_before: Option<SystemTime>,
+    /// Excludes hidden files and directories. On Unix, these are ones whose names start with a `.`,
+    /// and on Windows, ones with the hidden attribute.
+    pub exclude_hidden: bool,
+    /// Files and directories of these types are excluded.
+    pub exclude_kinds: Vec<FileKind>,
+}
+
+impl MetadataFilter {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Default::default()
+    }
+
+    /// Returns true if the file or directory at the path should be synced.
+    pub fn is_included(&self, path: &Path, meta: &Metadata) -> bool {
+        if self.exclude_kinds.contains(&FileKind::from_file_type(meta.file_type())) {
+            return false;
+        }
+        if self.exclude_hidden && is_hidden(path, meta) {
+            return false;
+        }
+        if !meta.is_file() {
+            return true;
+        }
+        if self.min_size.map_or(false, |min_size| meta.len() < min_size) ||
+           self.max_size.map_or(false, |max_size| meta.len() > max_size) {
+            return false;
+        }
+        if self.modified_after.is_some() || self.modified_before.is_some() {
+            // If the modified date can't be read, let the file through rather than guessing.
+            if let Ok(modified) = meta.modified() {
+                if self.modified_after.map_or(false, |after| modified < after) ||
+                   self.modified_before.map_or(false, |before| modified >= before) {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+}
+
+#[cfg(unix)]
+fn is_hidden(path: &Path, _meta: &Metadata) -> bool {
+    path.file_name().map_or(false, |name| name.to_string_lossy().starts_with('.'))
+}
+
+#[cfg(windows)]
+fn is_hidden(_path: &Path, meta: &Metadata) -> bool {
+    use std::os::windows::fs::MetadataExt;
+    const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+    meta.file_attributes() & FILE_ATTRIBUTE_HIDDEN != 0
+}
+
+fn is_double_star(segment: &[char]) -> bool {
+    segment == ['*', '*']
+}
+
+fn match_segments(segments: &[Vec<char>], components: &[&str]) -> bool {
+    let components: Vec<Vec<char>> = components.iter().map(|c| c.chars().collect()).collect();
+    match segments.last() {
+        // A trailing `**` matches everything inside, but not the directory itself, so it needs at
+        // least one component. Matching the last component by itself does the same thing.
+        Some(segment) if is_double_star(segment) => {
+            !components.is_empty() &&
+            match_components(segments, &components[..components.len() - 1])
+        },
+        _ => match_components(segments, &components),
+    }
+}
+
+// Both of these use the usual wildcard matching algorithm, which only backtracks to the last `**`
+// or `*` instead of trying every way to match the earlier ones, so that a pattern with a lot of
+// them can't take exponential time.
+
+fn match_components(segments: &[Vec<char>], components: &[Vec<char>]) -> bool {
+    let (mut s, mut c) = (0, 0);
+    // The segment after the last `**` and the first component it was tried against.
+    let mut backtrack = None;
+    while c < components.len() {
+        if s < segments.len() && is_double_star(&segments[s]) {
+            s += 1;
+            backtrack = Some((s, c));
+        } else if s < segments.len() && glob_match(&segments[s], &components[c]) {
+            s += 1;
+            c += 1;
+        } else if let Some((backtrack_s, backtrack_c)) = backtrack {
+            // Let the `**` match one more component.
+            s = backtrack_s;
+            c = backtrack_c + 1;
+            backtrack = Some((backtrack_s, c));
+        } else {
+            return false;
+        }
+    }
+    segments[s..].iter().all(|segment| is_double_star(segment))
+}
+
+fn glob_match(pattern: &[char], name: &[char]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    let mut backtrack = None;
+    while n < name.len() {
+        if pattern.get(p) == Some(&'*') {
+            p += 1;
+            backtrack = Some((p, n));
+        } else if let Some(len) = match_char(&pattern[p..], name[n]) {
+            p += len;
+            n += 1;
+        } else if let Some((backtrack_p, backtrack_n)) = backtrack {
+            // Let the `*` match one more character.
+            p = backtrack_p;
+            n = backtrack_n + 1;
+            backtrack = Some((backtrack_p, n));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&ch| ch == '*')
+}
+
+// Matches one character against the start of the pattern, which isn't a `*`. Returns the length of
+// the part of the pattern that matched it.
+fn match_char(pattern: &[char], c: char) -> Option<usize> {
+    match pattern.first() {
+        None => None,
+        Some(&'?') => Some(1),
+        Some(&'[') => {
+            match match_class(&pattern[1..], Some(&c)) {
+                Some((true, class_len)) => Some(1 + class_len),
+                Some((false, _)) => None,
+                // There is no closing bracket, so match the `[` literally.
+                None => if c == '[' { Some(1) } else { None },
+            }
+        },
+        Some(&'\\') if pattern.len() > 1 => if pattern[1] == c { Some(2) } else { None },
+        Some(&ch) => if ch == c { Some(1) } else { None },
+    }
+}
+
+// Matches a character class like `abc]`, `!a-z]` or `]x]` (the `[` is already removed). Returns
+// whether the character matched and the length of the class including the `]`, or `None` if the
+// class isn't closed.
+fn match_class(class: &[char], c: Option<&char>) -> Option<(bool, usize)> {
+    let negated = class.first() == Some(&'!') || class.first() == Some(&'^');
+    let start = if negated { 1 } else { 0 };
+    // A `]` right at the start is part of the class instead of closing it.
+    let end = class.iter().skip(start + 1).position(|&ch| ch == ']')? + start + 1;
+    let c = match c {
+        Some(&c) => c,
+        None => return Some((false, end + 1)),
+    };
+    let items = &class[start..end];
+    let mut matched = false;
+    let mut i = 0;
+    while i < items.len() {
+        if i + 2 < items.len() && items[i + 1] == '-' {
+            matched |= items[i] <= c && c <= items[i + 2];
+            i += 3;
+        } else {
+            matched |= items[i] == c;
+            i += 1;
+        }
+    }
+    Some((matched != negated, end + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+    use std::time::{Duration, SystemTime};
+    use super::{FileKind, FilterRule, FilterRules, MetadataFilter, RuleKind};
+
+    fn rule(s: &str) -> FilterRule {
+        s.parse().expect("failed to parse rule")
+    }
+
+    #[test]
+    fn test_parse_rule() {
+        assert_eq!(rule("+ *.txt"), FilterRule::include("*.txt"));
+        assert_eq!(rule("- *.txt"), FilterRule::exclude("*.txt"));
+        assert_eq!(rule("!*.txt"), FilterRule::include("*.txt"));
+        assert_eq!(rule("*.txt"), FilterRule::exclude("*.txt"));
+        assert_eq!(rule("target/").to_string(), "- target/");
+        assert!("".parse::<FilterRule>().is_err());
+        assert!("+ /".parse::<FilterRule>().is_err());
+    }
+
+    #[test]
+    fn test_rule_matches() {
+        let floating = rule("*.tmp");
+        assert!(floating.matches(Path::new("a.tmp"), false));
+        assert!(floating.matches(Path::new("x/y/a.tmp"), false));
+        assert!(!floating.matches(Path::new("a.tmp/b"), false));
+
+        let anchored = rule("/build");
+        assert!(anchored.matches(Path::new("build"), true));
+        assert!(!anchored.matches(Path::new("src/build"), true));
+
+        let nested = rule("docs/*.md");
+        assert!(nested.matches(Path::new("docs/a.md"), false));
+        assert!(!nested.matches(Path::new("x/docs/a.md"), false));
+
+        let dir_only = rule("target/");
+        assert!(dir_only.matches(Path::new("crate/target"), true));
+        assert!(!dir_only.matches(Path::new("crate/target"), false));
+
+        let double_star = rule("**/cache/**");
+        assert!(double_star.matches(Path::new("cache/a"), false));
+        assert!(double_star.matches(Path::new("x/y/cache/a/b"), false));
+        assert!(!double_star.matches(Path::new("x/cache"), true));
+
+        let middle = rule("a/**/z");
+        assert!(middle.matches(Path::new("a/z"), false));
+        assert!(middle.matches(Path::new("a/b/c/z"), false));
+
+        let class = rule("file[0-9!].txt");
+        assert!(class.matches(Path::new("file7.txt"), false));
+        assert!(class.matches(Path::new("file!.txt"), false));
+        assert!(!class.matches(Path::new("filex.txt"), false));
+        assert!(rule("[!a]?").matches(Path::new("bc"), false));
+        assert!(rule("\\*").matches(Path::new("*"), false));
+        assert!(!rule("\\*").matches(Path::new("a"), false));
+    }
+
+    #[test]
+    fn test_many_wildcards() {
+        // These would take far too long if every way of matching the wildcards were tried.
+        let name = "a".repeat(60);
+        assert!(!rule(&format!("{}b", "*a".repeat(30))).matches(Path::new(&name), false));
+        assert!(rule(&format!("{}*", "*a".repeat(30))).matches(Path::new(&name), false));
+        let path = vec!["a"; 60].join("/");
+        let pattern = format!("/{}b", "**/a/".repeat(20));
+        assert!(!rule(&pattern).matches(Path::new(&path), false));
+        let pattern = format!("/{}a", "**/a/".repeat(20));
+        assert!(rule(&pattern).matches(Path::new(&path), false));
+        assert!(rule("/a/**/**").matches(Path::new("a/b"), false));
+        assert!(!rule("/a/**/**").matches(Path::new("a"), true));
+    }
+
+    #[test]
+    fn test_rules_last_match_wins() {
+        let mut rules = FilterRules::new();
+        rules.push(rule("*.log"))
+             .push(rule("!important.log"))
+             .push(rule("/logs/"));
+        assert_eq!(rules.rules()[1].kind(), RuleKind::Include);
+        assert!(!rules.is_included(Path::new("a.log"), false));
+        assert!(rules.is_included(Path::new("important.log"), false));
+        assert!(rules.is_included(Path::new("a.txt"), false));
+        assert!(!rules.is_included(Path::new("logs"), true));
+        assert!(rules.is_included(Path::new("logs"), false));
+
+        let file_path = create_file("FilterRulesTestsFile");
+        let file_meta = fs::metadata(&file_path).expect("failed to read metadata");
+        let filter = rules.to_filter(vec![PathBuf::from("/files")]);
+        assert!(!filter(Path::new("/files/x/a.log"), &file_meta));
+        assert!(filter(Path::new("/files/x/important.log"), &file_meta));
+        assert!(filter(Path::new("/other/a.log"), &file_meta));
+
+        fs::remove_file(&file_path).expect("failed to delete FilterRulesTestsFile");
+    }
+
+    // Creates a 100 byte file in the temp directory and returns its path. Each test uses its own
+    // file so that tests running at the same time don't truncate each other's.
+    fn create_file(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(name);
+        let mut file = File::create(&path).expect("failed to create test file");
+        file.write_all(&[0; 100]).expect("failed to write test file");
+        path
+    }
+
+    #[test]
+    fn test_metadata_filter() {
+        let file_path = create_file("FilterMetadataTestsFile");
+        let file_meta = fs::metadata(&file_path).expect("failed to read metadata");
+        let dir_meta = fs::metadata(env::temp_dir()).expect("failed to read metadata");
+        let path = Path::new("a.bin");
+
+        let mut filter = MetadataFilter::new();
+        assert!(filter.is_empty());
+        filter.min_size = Some(101);
+        assert!(!filter.is_included(path, &file_meta));
+        assert!(filter.is_included(path, &dir_meta));
+        filter.min_size = Some(100);
+        filter.max_size = Some(100);
+        assert!(filter.is_included(path, &file_meta));
+        filter.max_size = Some(99);
+        assert!(!filter.is_included(path, &file_meta));
+
+        let mut filter = MetadataFilter::new();
+        filter.modified_after = Some(SystemTime::now() + Duration::from_secs(3600));
+        assert!(!filter.is_included(path, &file_meta));
+        filter.modified_after = None;
+        filter.modified_before = Some(SystemTime::now() + Duration::from_secs(3600));
+        assert!(filter.is_included(path, &file_meta));
+
+        let mut filter = MetadataFilter::new();
+        filter.exclude_kinds.push(FileKind::Dir);
+        assert!(filter.is_included(path, &file_meta));
+        assert!(!filter.is_included(path, &dir_meta));
+        assert_eq!("symlink".parse::<FileKind>(), Ok(FileKind::Symlink));
+
+        fs::remove_file(&file_path).expect("failed to delete FilterMetadataTestsFile");
+    }
+
+    #[test]
+    fn test_protect_rules() {
+        let mut rules = FilterRules::new();
+        rules.push(rule("P .backup-marker"))
+             .push(rule("*.log"))
+             .push(rule("P /config/"));
+        assert_eq!(rules.rules()[0], FilterRule::protect(".backup-marker"));
+        assert_eq!(rules.rules()[0].to_string(), "P .backup-marker");
+        assert!(rules.is_included(Path::new(".backup-marker"), false));
+        assert!(rules.is_protected(Path::new("a/.backup-marker"), false));
+        assert!(rules.is_protected(Path::new("config"), true));
+        assert!(!rules.is_protected(Path::new("config"), false));
+        assert!(!rules.is_protected(Path::new("a.log"), false));
+    }
+
+    #[test]
+    fn test_from_filter_file() {
+        let rules = FilterRules::from_filter_file("# build output\n\ntarget/\n*.log\n!keep.log\n");
+        assert_eq!(rules.rules(), &[rule("target/"), rule("*.log"), rule("!keep.log")]);
+    }
+
+    #[test]
+    fn test_from_gitignore() {
+        let text = "# comment\n\n- foo\nP bar\n!keep.log\n\\#hash\n\\!bang\ntrail  \nspace\\ \n/\n";
+        let rules = FilterRules::from_gitignore(text);
+        assert_eq!(rules.rules(), &[
+            FilterRule::exclude("- foo"),
+            FilterRule::exclude("P bar"),
+            FilterRule::include("keep.log"),
+            FilterRule::exclude("\\#hash"),
+            FilterRule::exclude("\\!bang"),
+            FilterRule::exclude("trail"),
+            FilterRule::exclude("space\\ "),
+        ]);
+        assert!(!rules.is_included(Path::new("- foo"), false));
+        assert!(rules.is_included(Path::new("foo"), false));
+        assert!(!rules.is_included(Path::new("#hash"), false));
+        assert!(!rules.is_included(Path::new("!bang"), false));
+        assert!(!rules.is_included(Path::new("space "), false));
+        assert!(rules.is_included(Path::new("space"), false));
+    }
+}
@@ -0,0 +1,474 @@
+use std::cell::RefCell;
+use std::cmp::min;
+use std::rc::Rc;
+use std::path::PathBuf;
+
+use clear_coat::*;
+use clear_coat::common_attrs_cbs::*;
+
+use mirror_sync::filter::FilterRule;
+use mirror_sync::jobs::{Job, JobStore};
+
+#[derive(Clone)]
+struct JobPageData {
+    control: Vbox,
+    name_text_box: Text,
+    parallel_copies_text_box: Text,
+    copy_if_size_mismatched_checkbox: Toggle,
+    copy_if_modified_mismatched_checkbox: Toggle,
+    copy_created_checkbox: Toggle,
+    copy_modified_checkbox: Toggle,
+    use_gitignore_checkbox: Toggle,
+    archive_dir_text_box: Text,
+
+    folder_list: List,
+    source_dir_text_box: Text,
+    dest_dir_text_box: Text,
+    add_dirs_button: Button,
+    delete_dirs_button: Button,
+
+    blacklist: List,
+    blacklist_text_box: Text,
+    blacklist_add_button: Button,
+    blacklist_delete_button: Button,
+
+    rules_list: List,
+    rules_text_box: Text,
+    rules_add_button: Button,
+    rules_delete_button: Button,
+}
+
+struct MainWindowInner {
+    jobs: Vec<Job>,
+
+    dialog: Dialog,
+    job_list: List,
+    job_page: JobPageData,
+}
+
+impl MainWindowInner {
+    fn load_jobs(&mut self) {
+        match JobStore::user_default().and_then(|store| store.load()) {
+            Ok(jobs) => self.jobs = jobs,
+            Err(err) => {
+                println!("{}", err);
+                // TODO: should show dialog
+                return;
+            },
+        }
+        self.update_job_list();
+        self.update_job_page();
+    }
+
+    fn save_jobs(&self) {
+        // TODO: I should create a timer and just start it here. When the timer goes off,
+        // it actually saves the jobs.
+        if let Err(err) = JobStore::user_default().and_then(|store| store.save(&self.jobs)) {
+            println!("{}", err);
+            // TODO: should show dialog
+        }
+    }
+
+    fn update_job_page(&self) {
+        let sel_index = if let Some(index) = self.job_list.value_single() {
+            index
+        } else {
+            return;
+        };
+        self.job_page.name_text_box.set_value(&self.jobs[sel_index].name);
+        self.job_page.parallel_copies_text_box.set_value(&self.jobs[sel_index].parallel_copies.to_string());
+        self.job_page.copy_if_size_mismatched_checkbox.set_on(
+            self.jobs[sel_index].copy_contents_if_size_mismatched);
+        self.job_page.copy_if_modified_mismatched_checkbox.set_on(
+            self.jobs[sel_index].copy_contents_if_date_mismatched);
+        self.job_page.copy_created_checkbox.set_on(self.jobs[sel_index].copy_created_date);
+        self.job_page.copy_modified_checkbox.set_on(self.jobs[sel_index].copy_modified_date);
+        self.job_page.use_gitignore_checkbox.set_on(self.jobs[sel_index].use_gitignore);
+        self.job_page.archive_dir_text_box.set_value(
+            &self.jobs[sel_index].archive_dir.as_ref().map_or("".into(), |dir| dir.to_string_lossy()));
+        self.job_page.folder_list.set_items(self.jobs[sel_index].directories.iter().map(|dir| {
+            format!("{} -> {}", dir.0.to_string_lossy(), dir.1.to_string_lossy())
+        }));
+        self.job_page.blacklist.set_items(self.jobs[sel_index].blacklist.iter().map(|path| {
+            path.to_string_lossy().into_owned()
+        }));
+        self.job_page.rules_list.set_items(self.jobs[sel_index].rules.rules().iter().map(|rule| {
+            rule.to_string()
+        }));
+    }
+
+    fn update_job_list(&self) {
+        let sel_index = self.job_list.value_single();
+        self.job_list.set_items(self.jobs.iter().map(|job| &job.name));
+        if !self.jobs.is_empty() {
+            self.job_list.set_value_single(sel_index.map(|i| min(i, self.jobs.len() - 1)));
+        }
+    }
+
+    fn add_new_job(&mut self) {
+        self.jobs.push(Default::default());
+        self.update_job_list();
+        self.job_list.set_value_single(Some(self.jobs.len() - 1));
+        self.update_job_page();
+        self.save_jobs();
+    }
+}
+
+const NAME_VISIBLE_COLUMNS: u32 = 15;
+
+#[derive(Clone)]
+pub struct MainWindow(Rc<RefCell<MainWindowInner>>);
+
+impl MainWindow {
+    pub fn new() -> Self {
+        let dialog = Dialog::new();
+
+        let job_list_label = Label::with_title("Jobs");
+        let job_list = List::new();
+        job_list.set_expand(Expand::Vertical);
+        job_list.set_visible_columns(NAME_VISIBLE_COLUMNS);
+        let add_job_button = Button::with_title("Add");
+        let delete_job_button = Button::with_title("Delete");
+
+        let job_page = Self::create_job_page();
+
+        let sync_button = Button::with_title("Sync");
+
+        let main_page = hbox!(
+            vbox!(
+                &job_list_label,
+                &job_list,
+                hbox!(&add_job_button, &delete_job_button),
+            ),
+            vbox!(
+                &job_page.control,
+                hbox!(fill!(),&sync_button),
+            ),
+        );
+        main_page.set_top_level_margin_and_gap();
+        dialog.append(&main_page).expect("failed to build the window");
+        dialog.set_title("Mirror Sync");
+
+        let job_list_tmp = job_list.clone();
+        let job_page_tmp = job_page.clone();
+        let main_window_zyg = MainWindow(Rc::new(RefCell::new(MainWindowInner {
+            jobs: vec![],
+            dialog: dialog,
+            job_list: job_list_tmp,
+            job_page: job_page_tmp,
+        })));
+
+        let main_window = main_window_zyg.clone();
+        job_list.action_event().add(move |_: &ListActionArgs|
+            main_window.0.borrow().update_job_page()
+        );
+        let main_window = main_window_zyg.clone();
+        add_job_button.action_event().add(move || main_window.0.borrow_mut().add_new_job());
+
+        let main_window = main_window_zyg.clone();
+        delete_job_button.action_event().add(move || {
+            let mut inner = main_window.0.borrow_mut();
+            if let Some(sel_index) = inner.job_list.value_single() {
+                inner.jobs.remove(sel_index);
+                inner.update_job_list();
+                inner.update_job_page();
+                inner.save_jobs();
+            }
+        });
+
+        let main_window = main_window_zyg.clone();
+        job_page.name_text_box.value_changed_event().add(move || {
+            let mut inner = main_window.0.borrow_mut();
+            if let Some(sel_index) = inner.job_list.value_single() {
+                inner.jobs[sel_index].name = inner.job_page.name_text_box.value();
+                inner.update_job_list();
+                inner.save_jobs();
+            };
+        });
+
+        let main_window = main_window_zyg.clone();
+        job_page.parallel_copies_text_box.value_changed_event().add(move || {
+            let mut inner = main_window.0.borrow_mut();
+            if let Some(sel_index) = inner.job_list.value_single() {
+                let parallel_str = inner.job_page.parallel_copies_text_box.value();
+                if let Ok(parallel_copies) = parallel_str.parse::<u8>() {
+                    inner.jobs[sel_index].parallel_copies = parallel_copies;
+                    inner.save_jobs();
+                }
+            }
+        });
+
+        let main_window = main_window_zyg.clone();
+        job_page.copy_if_size_mismatched_checkbox.action_event().add(move |checked| {
+            let mut inner = main_window.0.borrow_mut();
+            if let Some(sel_index) = inner.job_list.value_single() {
+                inner.jobs[sel_index].copy_contents_if_size_mismatched = checked;
+                inner.save_jobs();
+            }
+        });
+
+        let main_window = main_window_zyg.clone();
+        job_page.copy_if_modified_mismatched_checkbox.action_event().add(move |checked| {
+            let mut inner = main_window.0.borrow_mut();
+            if let Some(sel_index) = inner.job_list.value_single() {
+                inner.jobs[sel_index].copy_contents_if_date_mismatched = checked;
+                inner.save_jobs();
+            }
+        });
+
+        let main_window = main_window_zyg.clone();
+        job_page.copy_created_checkbox.action_event().add(move |checked| {
+            let mut inner = main_window.0.borrow_mut();
+            if let Some(sel_index) = inner.job_list.value_single() {
+                inner.jobs[sel_index].copy_created_date = checked;
+                inner.save_jobs();
+            }
+        });
+
+        let main_window = main_window_zyg.clone();
+        job_page.copy_modified_checkbox.action_event().add(move |checked| {
+            let mut inner = main_window.0.borrow_mut();
+            if let Some(sel_index) = inner.job_list.value_single() {
+                inner.jobs[sel_index].copy_modified_date = checked;
+                inner.save_jobs();
+            }
+        });
+
+        let main_window = main_window_zyg.clone();
+        job_page.use_gitignore_checkbox.action_event().add(move |checked| {
+            let mut inner = main_window.0.borrow_mut();
+            if let Some(sel_index) = inner.job_list.value_single() {
+                inner.jobs[sel_index].use_gitignore = checked;
+                inner.save_jobs();
+            }
+        });
+
+        let main_window = main_window_zyg.clone();
+        job_page.archive_dir_text_box.value_changed_event().add(move || {
+            let mut inner = main_window.0.borrow_mut();
+            if let Some(sel_index) = inner.job_list.value_single() {
+                let archive_dir = inner.job_page.archive_dir_text_box.value();
+                inner.jobs[sel_index].archive_dir = if archive_dir.is_empty() {
+                    None
+                } else {
+                    Some(PathBuf::from(archive_dir))
+                };
+                inner.save_jobs();
+            }
+        });
+
+        let main_window = main_window_zyg.clone();
+        job_page.add_dirs_button.action_event().add(move || {
+            let mut inner = main_window.0.borrow_mut();
+            if let Some(sel_index) = inner.job_list.value_single() {
+                let src = inner.job_page.source_dir_text_box.value();
+                let dest = inner.job_page.dest_dir_text_box.value();
+                inner.jobs[sel_index].directories.push((PathBuf::from(src), PathBuf::from(dest)));
+                inner.job_page.source_dir_text_box.set_value("");
+                inner.job_page.dest_dir_text_box.set_value("");
+                inner.update_job_page();
+                inner.save_jobs();
+            }
+        });
+
+        let main_window = main_window_zyg.clone();
+        job_page.delete_dirs_button.action_event().add(move || {
+            let mut inner = main_window.0.borrow_mut();
+            if let Some(sel_index) = inner.job_list.value_single() {
+                if let Some(sel_dir_index) = inner.job_page.folder_list.value_single() {
+                    inner.jobs[sel_index].directories.remove(sel_dir_index);
+                    inner.update_job_page();
+                    inner.save_jobs();
+                }
+            }
+        });
+
+        let main_window = main_window_zyg.clone();
+        job_page.blacklist_add_button.action_event().add(move || {
+            let mut inner = main_window.0.borrow_mut();
+            if let Some(sel_index) = inner.job_list.value_single() {
+                let path = inner.job_page.blacklist_text_box.value();
+                if path.is_empty() {
+                    return;
+                }
+                inner.jobs[sel_index].blacklist.push(PathBuf::from(path));
+                inner.job_page.blacklist_text_box.set_value("");
+                inner.update_job_page();
+                inner.save_jobs();
+            }
+        });
+
+        let main_window = main_window_zyg.clone();
+        job_page.blacklist_delete_button.action_event().add(move || {
+            let mut inner = main_window.0.borrow_mut();
+            if let Some(sel_index) = inner.job_list.value_single() {
+                if let Some(sel_path_index) = inner.job_page.blacklist.value_single() {
+                    inner.jobs[sel_index].blacklist.remove(sel_path_index);
+                    inner.update_job_page();
+                    inner.save_jobs();
+                }
+            }
+        });
+
+        let main_window = main_window_zyg.clone();
+        job_page.rules_add_button.action_event().add(move || {
+            let mut inner = main_window.0.borrow_mut();
+            if let Some(sel_index) = inner.job_list.value_single() {
+                let rule = match inner.job_page.rules_text_box.value().parse::<FilterRule>() {
+                    Ok(rule) => rule,
+                    Err(err) => {
+                        println!("{}", err);
+                        // TODO: should show dialog
+                        return;
+                    },
+                };
+                inner.jobs[sel_index].rules.push(rule);
+                inner.job_page.rules_text_box.set_value("");
+                inner.update_job_page();
+                inner.save_jobs();
+            }
+        });
+
+        let main_window = main_window_zyg.clone();
+        job_page.rules_delete_button.action_event().add(move || {
+            let mut inner = main_window.0.borrow_mut();
+            if let Some(sel_index) = inner.job_list.value_single() {
+                if let Some(sel_rule_index) = inner.job_page.rules_list.value_single() {
+                    inner.jobs[sel_index].rules.remove(sel_rule_index);
+                    inner.update_job_page();
+                    inner.save_jobs();
+                }
+            }
+        });
+
+        main_window_zyg.0.borrow_mut().load_jobs();
+
+        main_window_zyg
+    }
+
+    fn create_job_page() -> JobPageData {
+        let name_text_box = Text::new();
+        name_text_box.set_visible_columns(NAME_VISIBLE_COLUMNS);
+        let parallel_copies_text_box = Text::new();
+
+        let copy_if_size_mismatched_checkbox = Toggle::new();
+        copy_if_size_mismatched_checkbox.set_title("Size mismatched");
+        let copy_if_size_mismatched_indent = Label::new();
+        copy_if_size_mismatched_indent.set_min_size(10, 0);
+
+        let copy_if_modified_mismatched_checkbox = Toggle::new();
+        copy_if_modified_mismatched_checkbox.set_title("Date modified mismatched");
+        let copy_if_modified_mismatched_indent = Label::new();
+        copy_if_modified_mismatched_indent.set_min_size(10, 0);
+
+        let copy_created_checkbox = Toggle::new();
+        copy_created_checkbox.set_title("Copy created date");
+
+        let copy_modified_checkbox = Toggle::new();
+        copy_modified_checkbox.set_title("Copy modified date");
+
+        let use_gitignore_checkbox = Toggle::new();
+        use_gitignore_checkbox.set_title("Exclude files in .gitignore");
+
+        let archive_dir_text_box = Text::new();
+        archive_dir_text_box.set_expand(Expand::Horizontal);
+
+        let folder_list = List::new();
+        folder_list.set_expand(Expand::Yes);
+        folder_list.set_visible_columns(20);
+        folder_list.set_visible_lines(5);
+
+        let source_dir_text_box = Text::new();
+        source_dir_text_box.set_expand(Expand::Horizontal);
+        let dest_dir_text_box = Text::new();
+        dest_dir_text_box.set_expand(Expand::Horizontal);
+        let add_dirs_button = Button::with_title("Add");
+        let delete_dirs_button = Button::with_title("Delete");
+
+        let blacklist_text_box = Text::new();
+        blacklist_text_box.set_expand(Expand::Horizontal);
+        let blacklist_add_button = Button::with_title("Add");
+        let blacklist_delete_button = Button::with_title("Delete");
+
+        let blacklist = List::new();
+        blacklist.set_expand(Expand::Yes);
+        blacklist.set_visible_columns(20);
+
+        let rules_text_box = Text::new();
+        rules_text_box.set_expand(Expand::Horizontal);
+        let rules_add_button = Button::with_title("Add");
+        let rules_delete_button = Button::with_title("Delete");
+
+        let rules_list = List::new();
+        rules_list.set_expand(Expand::Yes);
+        rules_list.set_visible_columns(20);
+
+        let dirs_grid = grid_box!(
+            &Label::with_title("Source:"), &source_dir_text_box,
+            &Label::with_title("Destination:"), &dest_dir_text_box,
+        );
+        dirs_grid.set_num_div(NumDiv::Fixed(2)).fit_all_to_children();
+
+        let page = vbox!(
+            hbox!(&Label::with_title("Name:"), &name_text_box),
+            hbox!(&Label::with_title("Parallel jobs:"), &parallel_copies_text_box),
+            &Label::with_title("Copy file contents if"),
+            hbox!(copy_if_size_mismatched_indent, &copy_if_size_mismatched_checkbox),
+            hbox!(copy_if_modified_mismatched_indent, &copy_if_modified_mismatched_checkbox),
+            &copy_created_checkbox,
+            &copy_modified_checkbox,
+            &use_gitignore_checkbox,
+            hbox!(&Label::with_title("Archive folder:"), &archive_dir_text_box),
+            hbox!(
+                vbox!(
+                    &Label::with_title("Folders"), &folder_list,
+                    dirs_grid,
+                    hbox!(fill!(), &add_dirs_button, &delete_dirs_button),
+                ),
+                vbox!(
+                    &Label::with_title("Blacklist"), &blacklist,
+                    hbox!(&Label::with_title("Filter:"), &blacklist_text_box),
+                    hbox!(fill!(), &blacklist_add_button, &blacklist_delete_button),
+                ),
+                vbox!(
+                    &Label::with_title("Rules"), &rules_list,
+                    hbox!(&Label::with_title("Rule:"), &rules_text_box),
+                    hbox!(fill!(), &rules_add_button, &rules_delete_button),
+                ),
+            ),
+        );
+
+        JobPageData {
+            name_text_box: name_text_box,
+            parallel_copies_text_box: parallel_copies_text_box,
+            copy_if_size_mismatched_checkbox: copy_if_size_mismatched_checkbox,
+            copy_if_modified_mismatched_checkbox: copy_if_modified_mismatched_checkbox,
+            copy_created_checkbox: copy_created_checkbox,
+            copy_modified_checkbox: copy_modified_checkbox,
+            use_gitignore_checkbox: use_gitignore_checkbox,
+            archive_dir_text_box: archive_dir_text_box,
+
+            folder_list: folder_list,
+            source_dir_text_box: source_dir_text_box,
+            dest_dir_text_box: dest_dir_text_box,
+            add_dirs_button: add_dirs_button,
+            delete_dirs_button: delete_dirs_button,
+
+            blacklist: blacklist,
+            blacklist_text_box: blacklist_text_box,
+            blacklist_add_button: blacklist_add_button,
+            blacklist_delete_button: blacklist_delete_button,
+
+            rules_list: rules_list,
+            rules_text_box: rules_text_box,
+            rules_add_button: rules_add_button,
+            rules_delete_button: rules_delete_button,
+            control: page,
+        }
+    }
+
+    pub fn dialog(&self) -> Dialog {
+        self.0.borrow().dialog.clone()
+    }
+
+}
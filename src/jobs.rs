@@ -0,0 +1,497 @@
+use std::cmp;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use app_dirs;
+use serde_json;
+use serde_json::Value as JsonValue;
+use serde_json::builder::ObjectBuilder;
+
+use filter::{FileKind, FilterRule, FilterRules, MetadataFilter};
+use sync::{SymlinkPolicy, SyncBuilder};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Job {
+    pub name: String,
+    pub parallel_copies: u8,
+    pub copy_contents_if_date_mismatched: bool,
+    pub copy_contents_if_size_mismatched: bool,
+    pub copy_created_date: bool,
+    pub copy_modified_date: bool,
+    pub copy_permissions: bool,
+    pub copy_ownership: bool,
+    pub copy_xattrs: bool,
+    pub preserve_hard_links: bool,
+    pub directories: Vec<(PathBuf, PathBuf)>,
+    /// Paths in the source that aren't synced. They are not copied and are deleted from the
+    /// destination. An absolute path excludes that file or directory. A relative path is relative
+    /// to the source directory of each directory pair.
+    pub blacklist: Vec<PathBuf>,
+    /// Include and exclude rules that are applied after the blacklist.
+    pub rules: FilterRules,
+    /// Size, date, and type limits on the files that are synced.
+    pub metadata_filter: MetadataFilter,
+    /// If true, `.gitignore` files in the source are used as filter files in addition to
+    /// `.mirrorignore` files.
+    pub use_gitignore: bool,
+    /// What is done with symlinks in the source.
+    pub symlink_policy: SymlinkPolicy,
+    /// Where deleted and replaced files are moved, if anywhere. See `SyncBuilder::archive_dir()`.
+    pub archive_dir: Option<PathBuf>,
+    pub archive_keep_versions: u32,
+    pub archive_keep_days: u32,
+    /// The deletion guard. See `SyncBuilder::max_deleted_entries()`.
+    pub max_deleted_entries: u64,
+    pub max_deleted_percent: u8,
+    pub refuse_empty_source: bool,
+    /// A file that must be in both directories of a pair. See `SyncBuilder::marker_file()`.
+    pub marker_file: Option<String>,
+}
+
+impl Default for Job {
+    fn default() -> Self {
+        Job {
+            name: "Unnamed".into(),
+            parallel_copies: 2,
+            copy_contents_if_date_mismatched: false,
+            copy_contents_if_size_mismatched: true,
+            copy_created_date: true,
+            copy_modified_date: true,
+            copy_permissions: false,
+            copy_ownership: false,
+            copy_xattrs: false,
+            preserve_hard_links: false,
+            directories: vec![],
+            blacklist: vec![],
+            rules: FilterRules::new(),
+            metadata_filter: MetadataFilter::new(),
+            use_gitignore: false,
+            symlink_policy: SymlinkPolicy::Skip,
+            archive_dir: None,
+            archive_keep_versions: 0,
+            archive_keep_days: 0,
+            max_deleted_entries: 0,
+            max_deleted_percent: 0,
+            refuse_empty_source: true,
+            marker_file: None,
+        }
+    }
+}
+
+impl Job {
+    /// Returns a `SyncBuilder` set up with this job's options and directories.
+    pub fn sync_builder(&self) -> SyncBuilder {
+        let mut builder = SyncBuilder::new();
+        builder.parallel_copies(self.parallel_copies)
+               .copy_contents_if_date_mismatched(self.copy_contents_if_date_mismatched)
+               .copy_contents_if_size_mismatched(self.copy_contents_if_size_mismatched)
+               .copy_created_date(self.copy_created_date)
+               .copy_modified_date(self.copy_modified_date)
+               .copy_permissions(self.copy_permissions)
+               .copy_ownership(self.copy_ownership)
+               .copy_xattrs(self.copy_xattrs)
+               .preserve_hard_links(self.preserve_hard_links);
+        for &(ref src, ref dest) in &self.directories {
+            builder.add_directory_pair(src.clone(), dest.clone());
+        }
+        if self.use_gitignore {
+            builder.filter_file_names(&[".mirrorignore", ".gitignore"]);
+        }
+        if !self.blacklist.is_empty() {
+            let job = self.clone();
+            builder.filter(move |path, _| !job.is_blacklisted(path));
+        }
+        builder.filter_rules(&self.rules);
+        builder.metadata_filter(self.metadata_filter.clone())
+               .symlink_policy(self.symlink_policy)
+               .max_deleted_entries(self.max_deleted_entries)
+               .max_deleted_percent(self.max_deleted_percent)
+               .refuse_empty_source(self.refuse_empty_source);
+        if let Some(ref marker_file) = self.marker_file {
+            builder.marker_file(marker_file.as_str());
+        }
+        if let Some(ref archive_dir) = self.archive_dir {
+            builder.archive_dir(archive_dir.clone())
+                   .archive_keep_versions(self.archive_keep_versions)
+                   .archive_keep_days(self.archive_keep_days);
+        }
+        builder
+    }
+
+    /// Returns true if the source path is in the blacklist or inside a directory in the blacklist.
+    pub fn is_blacklisted(&self, path: &Path) -> bool {
+        self.blacklist.iter().any(|entry| {
+            if entry.is_absolute() {
+                path.starts_with(entry)
+            } else {
+                self.directories.iter().any(|&(ref src, _)| {
+                    path.strip_prefix(src).map_or(false, |rel_path| rel_path.starts_with(entry))
+                })
+            }
+        })
+    }
+}
+
+/// The settings file that jobs are saved in.
+#[derive(Debug, Clone)]
+pub struct JobStore {
+    path: PathBuf,
+}
+
+impl JobStore {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        JobStore { path: path.into() }
+    }
+
+    /// Returns the store in the user's data directory, which is where the main window saves jobs.
+    pub fn user_default() -> Result<Self, String> {
+        let settings_dir = app_dirs::get_data_root(app_dirs::AppDataType::UserData)
+            .map_err(|err| format!("failed to get settings directory: {}", err))?;
+        Ok(JobStore::new(settings_dir.join("MirrorSync").join("settings.json")))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Loads the jobs from the settings file.
+    pub fn load(&self) -> Result<Vec<Job>, String> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(err) => return Err(format!("failed to open file to load jobs: {}", err)),
+        };
+        let reader = BufReader::new(file);
+
+        let value: JsonValue = match serde_json::from_reader(reader) {
+            Ok(v) => v,
+            Err(err) => return Err(format!("failed to parse settings file as JSON: {}", err)),
+        };
+
+        let mut jobs = vec![];
+        if let Some(&JsonValue::Array(ref jobs_arr)) = value.find("jobs") {
+            for job_obj in jobs_arr {
+                let mut job: Job = Default::default();
+                if let Some(&JsonValue::String(ref name)) = job_obj.find("name") {
+                    job.name = name.clone();
+                }
+                if let Some(parallel_copies) = job_obj.find("parallel_copies")
+                                                      .and_then(|val| val.as_u64()) {
+                    job.parallel_copies = parallel_copies as u8;
+                }
+                if let Some(&JsonValue::Bool(b)) = job_obj.find("copy_contents_if_date_mismatched") {
+                    job.copy_contents_if_date_mismatched = b;
+                }
+                if let Some(&JsonValue::Bool(b)) = job_obj.find("copy_contents_if_size_mismatched") {
+                    job.copy_contents_if_size_mismatched = b;
+                }
+                if let Some(&JsonValue::Bool(b)) = job_obj.find("copy_created_date") {
+                    job.copy_created_date = b;
+                }
+                if let Some(&JsonValue::Bool(b)) = job_obj.find("copy_modified_date") {
+                    job.copy_modified_date = b;
+                }
+                if let Some(&JsonValue::Bool(b)) = job_obj.find("copy_permissions") {
+                    job.copy_permissions = b;
+                }
+                if let Some(&JsonValue::Bool(b)) = job_obj.find("copy_ownership") {
+                    job.copy_ownership = b;
+                }
+                if let Some(&JsonValue::Bool(b)) = job_obj.find("copy_xattrs") {
+                    job.copy_xattrs = b;
+                }
+                if let Some(&JsonValue::Bool(b)) = job_obj.find("preserve_hard_links") {
+                    job.preserve_hard_links = b;
+                }
+                if let Some(&JsonValue::Bool(b)) = job_obj.find("use_gitignore") {
+                    job.use_gitignore = b;
+                }
+                if let Some(policy) = job_obj.find("symlink_policy").and_then(|v| v.as_str())
+                                             .and_then(|s| s.parse::<SymlinkPolicy>().ok()) {
+                    job.symlink_policy = policy;
+                }
+                if let Some(&JsonValue::String(ref archive_dir)) = job_obj.find("archive_dir") {
+                    job.archive_dir = Some(PathBuf::from(archive_dir));
+                }
+                if let Some(keep_versions) = job_obj.find("archive_keep_versions").and_then(|v| v.as_u64()) {
+                    job.archive_keep_versions = keep_versions as u32;
+                }
+                if let Some(keep_days) = job_obj.find("archive_keep_days").and_then(|v| v.as_u64()) {
+                    job.archive_keep_days = keep_days as u32;
+                }
+                if let Some(max) = job_obj.find("max_deleted_entries").and_then(|v| v.as_u64()) {
+                    job.max_deleted_entries = max;
+                }
+                if let Some(max) = job_obj.find("max_deleted_percent").and_then(|v| v.as_u64()) {
+                    job.max_deleted_percent = cmp::min(max, 100) as u8;
+                }
+                if let Some(&JsonValue::Bool(b)) = job_obj.find("refuse_empty_source") {
+                    job.refuse_empty_source = b;
+                }
+                if let Some(&JsonValue::String(ref marker_file)) = job_obj.find("marker_file") {
+                    job.marker_file = Some(marker_file.clone());
+                }
+                if let Some(&JsonValue::Array(ref pair_arr)) = job_obj.find("directories") {
+                    let mut dirs = vec![];
+                    for pair_obj in pair_arr {
+                        let src = pair_obj.find("source");
+                        let dest = pair_obj.find("destination");
+                        if let (Some(&JsonValue::String(ref src)),
+                                Some(&JsonValue::String(ref dest))) = (src, dest) {
+                            dirs.push((PathBuf::from(src), PathBuf::from(dest)));
+                        }
+                    }
+                    job.directories = dirs;
+                }
+                if let Some(&JsonValue::Array(ref blacklist_arr)) = job_obj.find("blacklist") {
+                    job.blacklist = blacklist_arr.iter().filter_map(|val| val.as_str())
+                                                        .map(PathBuf::from).collect();
+                }
+                // A rule that can't be loaded is an error instead of being skipped, because
+                // syncing without a rule that excludes or protects files could delete them.
+                if let Some(filter_obj) = job_obj.find("metadata_filter") {
+                    job.metadata_filter = load_metadata_filter(filter_obj).map_err(|err| {
+                        format!("failed to load file type of job \"{}\": {}", job.name, err)
+                    })?;
+                }
+                if let Some(&JsonValue::Array(ref rules_arr)) = job_obj.find("rules") {
+                    for rule in rules_arr.iter().filter_map(|val| val.as_str()) {
+                        match rule.parse::<FilterRule>() {
+                            Ok(rule) => { job.rules.push(rule); },
+                            Err(err) => {
+                                return Err(format!("failed to load filter rule of job \"{}\": {}",
+                                                   job.name, err));
+                            },
+                        }
+                    }
+                }
+                jobs.push(job);
+            }
+        }
+        Ok(jobs)
+    }
+
+    /// Saves the jobs to the settings file, replacing any jobs already saved.
+    pub fn save(&self, jobs: &[Job]) -> Result<(), String> {
+        if let Some(dir) = self.path.parent() {
+            if let Err(err) = fs::create_dir_all(dir) {
+                return Err(format!("failed to create directory to save jobs: {}", err));
+            }
+        }
+
+        let json = ObjectBuilder::new()
+            .insert_array("jobs", |mut builder| {
+                for job in jobs.iter() {
+                    builder = builder.push_object(|job_builder| {
+                        job_builder
+                            .insert("name", &job.name)
+                            .insert("parallel_copies", job.parallel_copies)
+                            .insert("copy_contents_if_date_mismatched", job.copy_contents_if_date_mismatched)
+                            .insert("copy_contents_if_size_mismatched", job.copy_contents_if_size_mismatched)
+                            .insert("copy_created_date", job.copy_created_date)
+                            .insert("copy_modified_date", job.copy_modified_date)
+                            .insert("copy_permissions", job.copy_permissions)
+                            .insert("copy_ownership", job.copy_ownership)
+                            .insert("copy_xattrs", job.copy_xattrs)
+                            .insert("preserve_hard_links", job.preserve_hard_links)
+                            .insert("use_gitignore", job.use_gitignore)
+                            .insert("symlink_policy", job.symlink_policy.to_string())
+                            .insert("archive_dir", &job.archive_dir)
+                            .insert("archive_keep_versions", job.archive_keep_versions)
+                            .insert("archive_keep_days", job.archive_keep_days)
+                            .insert("max_deleted_entries", job.max_deleted_entries)
+                            .insert("max_deleted_percent", job.max_deleted_percent)
+                            .insert("refuse_empty_source", job.refuse_empty_source)
+                            .insert("marker_file", &job.marker_file)
+                            .insert_array("directories", |mut dir_arr_builder| {
+                                for dir in &job.directories {
+                                    dir_arr_builder = dir_arr_builder.push_object(|dir_pair_builder| {
+                                        dir_pair_builder.insert("source", &dir.0)
+                                                        .insert("destination", &dir.1)
+                                    });
+                                }
+                                dir_arr_builder
+                            })
+                            .insert_array("blacklist", |mut blacklist_arr_builder| {
+                                for path in &job.blacklist {
+                                    blacklist_arr_builder = blacklist_arr_builder.push(path);
+                                }
+                                blacklist_arr_builder
+                            })
+                            .insert_array("rules", |mut rules_arr_builder| {
+                                for rule in job.rules.rules() {
+                                    rules_arr_builder = rules_arr_builder.push(rule.to_string());
+                                }
+                                rules_arr_builder
+                            })
+                            .insert_object("metadata_filter", |filter_builder| {
+                                let filter = &job.metadata_filter;
+                                filter_builder
+                                    .insert("min_size", filter.min_size)
+                                    .insert("max_size", filter.max_size)
+                                    .insert("modified_after", filter.modified_after.map(time_to_secs))
+                                    .insert("modified_before", filter.modified_before.map(time_to_secs))
+                                    .insert("exclude_hidden", filter.exclude_hidden)
+                                    .insert_array("exclude_kinds", |mut kinds_arr_builder| {
+                                        for kind in &filter.exclude_kinds {
+                                            kinds_arr_builder = kinds_arr_builder.push(kind.to_string());
+                                        }
+                                        kinds_arr_builder
+                                    })
+                            })
+                    });
+                }
+                builder
+            })
+            .build();
+        let file = match File::create(&self.path) {
+            Ok(file) => file,
+            Err(err) => return Err(format!("failed to create file to save jobs: {}", err)),
+        };
+        let mut writer = BufWriter::new(file);
+
+        if let Err(err) = serde_json::ser::to_writer_pretty(&mut writer, &json) {
+            return Err(format!("failed to save jobs: {}", err));
+        }
+        Ok(())
+    }
+}
+
+fn load_metadata_filter(filter_obj: &JsonValue) -> Result<MetadataFilter, String> {
+    let mut filter = MetadataFilter::new();
+    filter.min_size = filter_obj.find("min_size").and_then(|val| val.as_u64());
+    filter.max_size = filter_obj.find("max_size").and_then(|val| val.as_u64());
+    filter.modified_after = filter_obj.find("modified_after").and_then(|val| val.as_i64())
+                                                              .map(secs_to_time);
+    filter.modified_before = filter_obj.find("modified_before").and_then(|val| val.as_i64())
+                                                                .map(secs_to_time);
+    if let Some(&JsonValue::Bool(b)) = filter_obj.find("exclude_hidden") {
+        filter.exclude_hidden = b;
+    }
+    if let Some(&JsonValue::Array(ref kinds_arr)) = filter_obj.find("exclude_kinds") {
+        for kind in kinds_arr.iter().filter_map(|val| val.as_str()) {
+            filter.exclude_kinds.push(kind.parse::<FileKind>()?);
+        }
+    }
+    Ok(filter)
+}
+
+// Dates are saved as the number of seconds since the Unix epoch.
+fn time_to_secs(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(dur) => dur.as_secs() as i64,
+        Err(err) => -(err.duration().as_secs() as i64),
+    }
+}
+
+fn secs_to_time(secs: i64) -> SystemTime {
+    if secs >= 0 {
+        UNIX_EPOCH + Duration::from_secs(secs as u64)
+    } else {
+        UNIX_EPOCH - Duration::from_secs(secs.wrapping_neg() as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::time::{Duration, UNIX_EPOCH};
+    use filter::{FileKind, FilterRule, FilterRules, MetadataFilter};
+    use sync::SymlinkPolicy;
+    use super::{Job, JobStore};
+
+    #[test]
+    fn test_save_load_jobs() {
+        let settings_dir = env::temp_dir().join("JobStoreTests");
+        let _ = fs::remove_dir_all(&settings_dir);
+        let store = JobStore::new(settings_dir.join("settings.json"));
+
+        let mut rules = FilterRules::new();
+        rules.push(FilterRule::exclude("target/")).push(FilterRule::include("/keep/**"));
+        let jobs = vec![
+            Job {
+                name: "Backup".into(),
+                parallel_copies: 4,
+                copy_contents_if_date_mismatched: true,
+                copy_contents_if_size_mismatched: false,
+                copy_created_date: false,
+                copy_modified_date: true,
+                copy_permissions: true,
+                copy_ownership: false,
+                copy_xattrs: true,
+                preserve_hard_links: true,
+                directories: vec![(PathBuf::from("/src"), PathBuf::from("/dest"))],
+                blacklist: vec![PathBuf::from("/src/Dev"), PathBuf::from("Temp")],
+                rules: rules,
+                metadata_filter: MetadataFilter {
+                    min_size: Some(1),
+                    max_size: None,
+                    modified_after: Some(UNIX_EPOCH + Duration::from_secs(1_500_000_000)),
+                    modified_before: None,
+                    exclude_hidden: true,
+                    exclude_kinds: vec![FileKind::Symlink],
+                },
+                use_gitignore: true,
+                symlink_policy: SymlinkPolicy::Copy,
+                archive_dir: Some(PathBuf::from("/archive")),
+                archive_keep_versions: 5,
+                archive_keep_days: 0,
+                max_deleted_entries: 1000,
+                max_deleted_percent: 10,
+                refuse_empty_source: false,
+                marker_file: Some(".mirror-sync-drive".into()),
+            },
+            Default::default(),
+        ];
+        store.save(&jobs).expect("failed to save jobs");
+        assert_eq!(store.load().expect("failed to load jobs"), jobs);
+
+        let _ = fs::remove_dir_all(&settings_dir).expect("failed to delete JobStoreTests");
+    }
+
+    #[test]
+    fn test_load_invalid_rules() {
+        let settings_dir = env::temp_dir().join("JobStoreInvalidTests");
+        let _ = fs::remove_dir_all(&settings_dir);
+        fs::create_dir(&settings_dir).expect("failed to create JobStoreInvalidTests");
+        let settings_path = settings_dir.join("settings.json");
+        let store = JobStore::new(settings_path.clone());
+
+        // Loading fails instead of leaving out a rule.
+        fs::write(&settings_path, r#"{"jobs": [{"name": "Backup", "rules": ["- /"]}]}"#)
+            .expect("failed to create settings.json");
+        assert_eq!(store.load(),
+                   Err("failed to load filter rule of job \"Backup\": filter rule \"- /\" has no \
+                        pattern".to_string()));
+        let settings = r#"{"jobs": [{"name": "Backup",
+                                     "metadata_filter": {"exclude_kinds": ["pipe"]}}]}"#;
+        fs::write(&settings_path, settings).expect("failed to create settings.json");
+        assert_eq!(store.load(),
+                   Err("failed to load file type of job \"Backup\": unknown file type \"pipe\""
+                       .to_string()));
+
+        let _ = fs::remove_dir_all(&settings_dir).expect("failed to delete JobStoreInvalidTests");
+    }
+
+    #[test]
+    fn test_is_blacklisted() {
+        let job = Job {
+            directories: vec![
+                (PathBuf::from("/files"), PathBuf::from("/backup/files")),
+                (PathBuf::from("/songs"), PathBuf::from("/backup/songs")),
+            ],
+            blacklist: vec![PathBuf::from("/files/Dev"), PathBuf::from("Temp")],
+            ..Default::default()
+        };
+        assert!(job.is_blacklisted(Path::new("/files/Dev")));
+        assert!(job.is_blacklisted(Path::new("/files/Dev/notes.txt")));
+        assert!(!job.is_blacklisted(Path::new("/files/Development")));
+        assert!(job.is_blacklisted(Path::new("/files/Temp")));
+        assert!(job.is_blacklisted(Path::new("/songs/Temp/a.wav")));
+        assert!(!job.is_blacklisted(Path::new("/songs/Old/Temp")));
+        assert!(!job.is_blacklisted(Path::new("/songs/a.wav")));
+    }
+}
@@ -0,0 +1,28 @@
+extern crate app_dirs;
+extern crate crossbeam;
+extern crate itertools;
+extern crate serde_json;
+
+#[cfg(unix)]
+extern crate libc;
+#[cfg(windows)]
+extern crate winapi;
+#[cfg(windows)]
+extern crate kernel32;
+
+#[cfg_attr(unix, path = "unix_file_times.rs")]
+#[cfg_attr(windows, path = "windows_file_times.rs")]
+mod file_times;
+#[cfg_attr(unix, path = "unix_permissions.rs")]
+#[cfg_attr(windows, path = "windows_permissions.rs")]
+mod permissions;
+#[cfg_attr(target_os = "linux", path = "linux_xattrs.rs")]
+#[cfg_attr(not(target_os = "linux"), path = "other_xattrs.rs")]
+mod xattrs;
+#[cfg_attr(target_os = "linux", path = "linux_sparse.rs")]
+#[cfg_attr(not(target_os = "linux"), path = "other_sparse.rs")]
+mod sparse;
+mod archive;
+pub mod filter;
+pub mod jobs;
+pub mod sync;
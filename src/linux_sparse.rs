@@ -0,0 +1,39 @@
+use std::cmp;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+
+use libc::{self, off_t};
+
+/// Returns the start and end of each region of the file's first `len` bytes that contains data,
+/// leaving out the holes of a sparse file. If the file system can't find holes, the whole file is
+/// one region. This moves the file's position.
+pub fn data_regions(file: &File, len: u64) -> Result<Vec<(u64, u64)>, io::Error> {
+    let fd = file.as_raw_fd();
+    let mut regions = vec![];
+    let mut pos = 0;
+    while pos < len {
+        let start = unsafe { libc::lseek(fd, pos as off_t, libc::SEEK_DATA) };
+        if start < 0 {
+            let err = io::Error::last_os_error();
+            match err.raw_os_error() {
+                // The rest of the file is a hole.
+                Some(libc::ENXIO) => break,
+                Some(libc::EINVAL) if pos == 0 => return Ok(vec![(0, len)]),
+                _ => return Err(err),
+            }
+        }
+        // There is always a hole at the end of the file, so this finds the end of the data.
+        let end = unsafe { libc::lseek(fd, start, libc::SEEK_HOLE) };
+        if end < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let (start, end) = (start as u64, cmp::min(end as u64, len));
+        if start >= end {
+            break;
+        }
+        regions.push((start, end));
+        pos = end;
+    }
+    Ok(regions)
+}
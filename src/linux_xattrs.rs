@@ -0,0 +1,101 @@
+use std::ffi::{CString, OsStr, OsString};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::ptr;
+
+use libc::{self, c_char, c_void, size_t, ssize_t};
+
+// POSIX ACLs are stored in the `system.posix_acl_access` and `system.posix_acl_default` extended
+// attributes, so they are read and written along with the rest.
+
+/// Returns the names and values of the extended attributes of the specified file, sorted by name.
+/// A file system that doesn't support extended attributes has none.
+pub fn read<P: AsRef<Path>>(file: P) -> Result<Vec<(OsString, Vec<u8>)>, io::Error> {
+    let path = CString::new(file.as_ref().as_os_str().as_bytes())?;
+    let names = read_buffer(|buffer, size| unsafe {
+        libc::listxattr(path.as_ptr(), buffer as *mut c_char, size)
+    });
+    let names = match names {
+        Ok(names) => names,
+        Err(ref err) if is_unsupported(err) => return Ok(vec![]),
+        Err(err) => return Err(err),
+    };
+    let mut attrs = vec![];
+    for name in names.split(|&b| b == 0).filter(|name| !name.is_empty()) {
+        let c_name = CString::new(name)?;
+        let value = read_buffer(|buffer, size| unsafe {
+            libc::getxattr(path.as_ptr(), c_name.as_ptr(), buffer as *mut c_void, size)
+        });
+        match value {
+            Ok(value) => attrs.push((OsStr::from_bytes(name).to_os_string(), value)),
+            // The attribute was removed after the names were listed.
+            Err(ref err) if err.raw_os_error() == Some(libc::ENODATA) => {},
+            Err(err) => return Err(err),
+        }
+    }
+    attrs.sort();
+    Ok(attrs)
+}
+
+/// Sets the extended attributes of the specified file to `attrs`, removing any others. A file
+/// system that doesn't support extended attributes is only an error if there are attributes to
+/// set.
+pub fn write<P: AsRef<Path>>(file: P, attrs: &[(OsString, Vec<u8>)]) -> Result<(), io::Error> {
+    let existing = read(&file)?;
+    let path = CString::new(file.as_ref().as_os_str().as_bytes())?;
+    for &(ref name, _) in &existing {
+        if attrs.iter().any(|&(ref attr_name, _)| attr_name == name) {
+            continue;
+        }
+        let c_name = CString::new(name.as_bytes())?;
+        unsafe {
+            if libc::removexattr(path.as_ptr(), c_name.as_ptr()) != 0 {
+                let err = io::Error::last_os_error();
+                if err.raw_os_error() != Some(libc::ENODATA) {
+                    return Err(err);
+                }
+            }
+        }
+    }
+    for attr in attrs {
+        if existing.contains(attr) {
+            continue;
+        }
+        let (ref name, ref value) = *attr;
+        let c_name = CString::new(name.as_bytes())?;
+        unsafe {
+            if libc::setxattr(path.as_ptr(), c_name.as_ptr(), value.as_ptr() as *const c_void,
+                              value.len(), 0) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns true if the error is because the file system doesn't support extended attributes.
+pub fn is_unsupported(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(libc::ENOTSUP)
+}
+
+// Calls a function that fills a buffer, like `listxattr()`, first to get the size needed and then
+// to fill a buffer of that size. It is called again if the size changed in between.
+fn read_buffer<F: Fn(*mut u8, size_t) -> ssize_t>(f: F) -> Result<Vec<u8>, io::Error> {
+    loop {
+        let size = f(ptr::null_mut(), 0);
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut buffer = vec![0; size as usize];
+        let size = f(buffer.as_mut_ptr(), buffer.len());
+        if size >= 0 {
+            buffer.truncate(size as usize);
+            return Ok(buffer);
+        }
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::ERANGE) {
+            return Err(err);
+        }
+    }
+}
@@ -0,0 +1,11 @@
+use std::fs::File;
+use std::io;
+
+// Holes are only found on Linux. Other systems either don't have sparse files or have different
+// APIs for them.
+
+/// Returns the start and end of each region of the file's first `len` bytes that contains data,
+/// which is the whole file on this system.
+pub fn data_regions(_file: &File, len: u64) -> Result<Vec<(u64, u64)>, io::Error> {
+    Ok(if len > 0 { vec![(0, len)] } else { vec![] })
+}
@@ -0,0 +1,29 @@
+use std::ffi::OsString;
+use std::io;
+use std::path::Path;
+
+// Extended attributes are only copied on Linux. Other systems have different APIs for them, or
+// none at all.
+
+/// Returns the names and values of the extended attributes of the specified file, which is none on
+/// this system.
+pub fn read<P: AsRef<Path>>(_file: P) -> Result<Vec<(OsString, Vec<u8>)>, io::Error> {
+    Ok(vec![])
+}
+
+/// Sets the extended attributes of the specified file to `attrs`, which is an error on this system
+/// unless there are none.
+pub fn write<P: AsRef<Path>>(_file: P, attrs: &[(OsString, Vec<u8>)]) -> Result<(), io::Error> {
+    if attrs.is_empty() {
+        Ok(())
+    } else {
+        Err(io::Error::new(io::ErrorKind::Other,
+                           "extended attributes aren't supported on this system"))
+    }
+}
+
+/// Returns true if the error is because the file system doesn't support extended attributes, which
+/// is the only error `write()` returns on this system.
+pub fn is_unsupported(_err: &io::Error) -> bool {
+    true
+}
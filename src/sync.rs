@@ -2,16 +2,27 @@
 use std::cmp::{self, Ordering};
 use std::collections::HashMap;
 use std::error::Error;
+use std::ffi::{OsStr, OsString};
 use std::fmt::{self, Debug};
-use std::fs::{self, File, Metadata};
-use std::io::{self, Read, Seek, SeekFrom};
+use std::fs::{self, DirEntry, File, Metadata};
+use std::mem;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::panic::{self, AssertUnwindSafe};
 use std::path::{PathBuf, Path};
-use std::sync::{Arc, Condvar, Mutex};
+use std::process;
+use std::str::FromStr;
+use std::sync::{mpsc, Arc, Condvar, Mutex};
 use std::thread;
-use std::time::Instant;
+use std::time::{Duration, Instant, SystemTime};
 use crossbeam;
 use crossbeam::sync::SegQueue;
 use itertools::{Itertools, Partition};
+use archive;
+use file_times;
+use filter::{FilterRules, MetadataFilter, RuleKind};
+use permissions;
+use sparse;
+use xattrs;
 
 #[derive(Clone)]
 pub struct SyncBuilder {
@@ -21,11 +32,39 @@ pub struct SyncBuilder {
     // Compares the first X bytes and last X bytes of the file and copies the file if they don't
     // match. Set to zero to turn off.
     copy_contents_if_start_end_mismatched_size: u32,
-    copy_contents_if_contents_mismatched: bool, // TODO: currently ignored
-    copy_created_date: bool,   // TODO: currently ignored
-    copy_modified_date: bool,   // TODO: currently ignored
+    // Compares every byte of the file and copies the file if they don't match. This reads both
+    // files in their entirety, so it is slow.
+    copy_contents_if_contents_mismatched: bool,
+    copy_created_date: bool,
+    copy_modified_date: bool,
+    copy_permissions: bool,
+    // Only used when running as root.
+    copy_ownership: bool,
+    copy_xattrs: bool,
+    preserve_hard_links: bool,
     directories: Vec<(PathBuf, PathBuf)>,
-    filter: Option<Arc<Fn(&Path) -> bool + Send + Sync>>,
+    filter: Option<Arc<Fn(&Path, &Metadata) -> bool + Send + Sync>>,
+    metadata_filter: MetadataFilter,
+    protect: Option<Arc<Fn(&Path, &Metadata) -> bool + Send + Sync>>,
+    // Applied to each directory pair like the rules of a filter file in the source directory.
+    filter_rules: FilterRules,
+    // The names of files in the source that contain filter rules for their directory.
+    filter_file_names: Vec<OsString>,
+    symlink_policy: SymlinkPolicy,
+    // Deleted and replaced files in the destination are moved here instead of being destroyed.
+    archive_dir: Option<PathBuf>,
+    // The number of versions of each file to keep in the archive. Set to zero to keep all of them.
+    archive_keep_versions: u32,
+    // Versions archived more than this many days ago are deleted. Set to zero to keep all of them.
+    archive_keep_days: u32,
+    // The deletion guard. The limits are turned off when zero.
+    max_deleted_entries: u64,
+    max_deleted_percent: u8,
+    refuse_empty_source: bool,
+    // If set, both directories of a pair must contain a file with this name to be synced.
+    marker_file: Option<OsString>,
+    // Set by `plan()` so that operations are reported instead of performed.
+    dry_run: bool,
 }
 
 impl SyncBuilder {
@@ -38,8 +77,25 @@ impl SyncBuilder {
             copy_contents_if_contents_mismatched: false,
             copy_created_date: true,
             copy_modified_date: true,
+            copy_permissions: false,
+            copy_ownership: false,
+            copy_xattrs: false,
+            preserve_hard_links: false,
             directories: vec![],
             filter: None,
+            metadata_filter: MetadataFilter::new(),
+            protect: None,
+            filter_rules: FilterRules::new(),
+            filter_file_names: vec![DEFAULT_FILTER_FILE_NAME.into()],
+            symlink_policy: SymlinkPolicy::Skip,
+            archive_dir: None,
+            archive_keep_versions: 0,
+            archive_keep_days: 0,
+            max_deleted_entries: 0,
+            max_deleted_percent: 0,
+            refuse_empty_source: true,
+            marker_file: None,
+            dry_run: false,
         }
     }
 
@@ -68,6 +124,8 @@ impl SyncBuilder {
         self
     }
 
+    /// Sets whether copied files get the created date of the source. It is only copied on Windows,
+    /// because Unix file systems don't allow changing it.
     pub fn copy_created_date(&mut self, value: bool) -> &mut Self {
         self.copy_created_date = value;
         self
@@ -78,22 +136,177 @@ impl SyncBuilder {
         self
     }
 
+    /// Sets whether the permission bits of files and directories in the destination are set to
+    /// those in the source. If only the permissions of a file are different, they are changed
+    /// without copying the contents. The destination directory of each pair is left alone.
+    /// Permissions aren't copied on Windows.
+    pub fn copy_permissions(&mut self, value: bool) -> &mut Self {
+        self.copy_permissions = value;
+        self
+    }
+
+    /// Sets whether the owner and group of files and directories in the destination are set to
+    /// those in the source, the same way as `copy_permissions()`. It is ignored unless the process
+    /// is running as root.
+    pub fn copy_ownership(&mut self, value: bool) -> &mut Self {
+        self.copy_ownership = value;
+        self
+    }
+
+    /// Sets whether the extended attributes of files and directories, which include POSIX ACLs,
+    /// are copied to the destination the same way as `copy_permissions()`. They are only copied on
+    /// Linux. If the file system of a destination directory doesn't support them, a
+    /// `SyncEvent::XattrsUnsupported` is logged, and the rest of the pair is synced without them.
+    pub fn copy_xattrs(&mut self, value: bool) -> &mut Self {
+        self.copy_xattrs = value;
+        self
+    }
+
+    /// Sets whether files that are hard linked together in the source are hard linked together in
+    /// the destination instead of being copied separately. This works across the directory pairs
+    /// of the operation. The links are made after every file has been copied, and a file is copied
+    /// if it can't be linked, as when the destinations are on different drives. Hard links are
+    /// only detected on Unix.
+    pub fn preserve_hard_links(&mut self, value: bool) -> &mut Self {
+        self.preserve_hard_links = value;
+        self
+    }
+
     pub fn add_directory_pair(&mut self, src: PathBuf, dest: PathBuf) -> &mut Self {
         self.directories.push((src, dest));
         self
     }
 
-    /// Adds a filter that will be passed the path to each file and directory in the source
-    /// before it is copied. If the function returns true, then the file/directory will be synced
-    /// normally. If it returns false, it will be as if the file/directory does not exist. It will
-    /// not be copied and will be deleted if it exists in the destination.
-    pub fn filter<F: Fn(&Path) -> bool + 'static + Send + Sync>(&mut self, f: F) -> &mut Self {
+    /// Adds a filter that will be passed the path and metadata of each file and directory in the
+    /// source before it is copied. If the function returns true, then the file/directory will be
+    /// synced normally. If it returns false, it will be as if the file/directory does not exist. It
+    /// will not be copied and will be deleted if it exists in the destination.
+    pub fn filter<F>(&mut self, f: F) -> &mut Self
+        where F: Fn(&Path, &Metadata) -> bool + 'static + Send + Sync
+    {
         // I'd kind of like to not have the closure be 'static, but then a lifetime parameter infects
         // SyncBuilder and SyncOperation.
         self.filter = Some(Arc::new(f));
         self
     }
 
+    /// Adds a function that will be passed the path and metadata of each file and directory in the
+    /// destination that would be deleted. If it returns true, the file/directory is kept. Keeping a
+    /// directory keeps everything inside it. A directory that isn't protected but has something
+    /// protected inside it is also kept, along with the protected things, and everything else in it
+    /// is deleted.
+    pub fn protect<F>(&mut self, f: F) -> &mut Self
+        where F: Fn(&Path, &Metadata) -> bool + 'static + Send + Sync
+    {
+        self.protect = Some(Arc::new(f));
+        self
+    }
+
+    /// Sets include, exclude, and protect rules that apply to every directory pair. Patterns are
+    /// matched against paths relative to the source and destination directories of each pair. The
+    /// rules work like a filter file in each source directory, so the rules of filter files take
+    /// precedence over them. They are checked in addition to `filter()` and `protect()`.
+    pub fn filter_rules(&mut self, rules: &FilterRules) -> &mut Self {
+        self.filter_rules = rules.clone();
+        self
+    }
+
+    /// Sets the size, date, and type limits on files that are synced. Files excluded by it are
+    /// treated the same as ones excluded by `filter()`.
+    pub fn metadata_filter(&mut self, metadata_filter: MetadataFilter) -> &mut Self {
+        self.metadata_filter = metadata_filter;
+        self
+    }
+
+    /// Sets the names of filter files, which is `.mirrorignore` by default. Like a `.gitignore`
+    /// file, each line of a filter file is a `FilterRule` that applies to the directory containing
+    /// the file and everything inside it. Rules in deeper directories take precedence. Files and
+    /// directories excluded by a filter file are treated the same as ones excluded by `filter()`.
+    /// A filter file named `.gitignore` is read with gitignore syntax instead.
+    pub fn filter_file_names<I, S>(&mut self, names: I) -> &mut Self
+        where I: IntoIterator<Item = S>, S: Into<OsString>
+    {
+        self.filter_file_names = names.into_iter().map(|name| name.into()).collect();
+        self
+    }
+
+    /// Sets what is done with symlinks in the source, which is `SymlinkPolicy::Skip` by default.
+    /// Symlinks in the destination are never followed, except for the destination directory of a
+    /// pair. One where the source has a file or directory is replaced, and one that isn't in the
+    /// source is deleted like a file.
+    pub fn symlink_policy(&mut self, policy: SymlinkPolicy) -> &mut Self {
+        self.symlink_policy = policy;
+        self
+    }
+
+    /// Sets a directory that files deleted from or replaced in the destination are moved to,
+    /// so that they can be recovered. Each archived file is named with the time the sync started,
+    /// like `notes.txt~20161203-142501`, and is put in the same place relative to the archive that
+    /// it was relative to the parent of the destination directory. A version archived in the same
+    /// second as an earlier one gets a number, like `notes.txt~20161203-142501.1`, so that it
+    /// doesn't replace it. The archive should not be inside a destination directory.
+    pub fn archive_dir(&mut self, dir: PathBuf) -> &mut Self {
+        self.archive_dir = Some(dir);
+        self
+    }
+
+    /// Sets how many versions of each file are kept in the archive. When a file is archived, its
+    /// oldest versions beyond this are deleted. Zero keeps every version.
+    pub fn archive_keep_versions(&mut self, versions: u32) -> &mut Self {
+        self.archive_keep_versions = versions;
+        self
+    }
+
+    /// Sets how many days versions are kept in the archive. Older versions are deleted when a sync
+    /// starts. Zero keeps every version.
+    pub fn archive_keep_days(&mut self, days: u32) -> &mut Self {
+        self.archive_keep_days = days;
+        self
+    }
+
+    /// Sets the most files and directories that may be deleted from the destination, counting
+    /// everything inside deleted directories. If more would be deleted, nothing is deleted and the
+    /// operation logs a `SyncEvent::DeletionsRefused` error. Zero means no limit.
+    ///
+    /// When any deletion limit is set, deletions wait until every directory has been scanned and
+    /// every file copied, so that the limits can be checked before anything is deleted. A file or
+    /// directory in the destination that is replaced by a directory or file from the source is
+    /// counted, along with everything inside it, and it isn't replaced until the limits are
+    /// checked.
+    pub fn max_deleted_entries(&mut self, max: u64) -> &mut Self {
+        self.max_deleted_entries = max;
+        self
+    }
+
+    /// Sets the largest percentage of the files and directories in the destination that may be
+    /// deleted. Zero means no limit.
+    pub fn max_deleted_percent(&mut self, max_percent: u8) -> &mut Self {
+        self.max_deleted_percent = max_percent;
+        self
+    }
+
+    /// If set, nothing is deleted from the destination when a source directory is empty or
+    /// missing, as when a drive isn't mounted. It is set by default, so deletions are held back
+    /// until the end of the sync like with the other limits. Turn it off to mirror a source that
+    /// is supposed to be empty.
+    pub fn refuse_empty_source(&mut self, refuse: bool) -> &mut Self {
+        self.refuse_empty_source = refuse;
+        self
+    }
+
+    /// Sets the name of a marker file that must be in both the source and destination directory of
+    /// a pair for it to be synced, so that a drive that isn't mounted or a different drive mounted
+    /// in its place isn't synced. The marker in the source is copied to the destination like any
+    /// other file.
+    pub fn marker_file<S: Into<OsString>>(&mut self, name: S) -> &mut Self {
+        self.marker_file = Some(name.into());
+        self
+    }
+
+    fn has_deletion_guard(&self) -> bool {
+        self.max_deleted_entries > 0 || self.max_deleted_percent > 0 || self.refuse_empty_source
+    }
+
     pub fn sync(&mut self) -> SyncOperation {
         let op = SyncOperation::new(&self);
         {
@@ -102,11 +315,21 @@ impl SyncBuilder {
         }
         op
     }
+
+    /// Starts a dry run that compares the source and destination the same way `sync()` does, but
+    /// doesn't modify the destination. Everything that `sync()` would have done can be read from
+    /// the returned operation with `SyncOperation::read_plan()`.
+    pub fn plan(&mut self) -> SyncOperation {
+        let mut options = self.clone();
+        options.dry_run = true;
+        options.sync()
+    }
 }
 
 impl Debug for SyncBuilder {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let filter_opt = self.filter.as_ref().map(|_| "closure");
+        let protect_opt = self.protect.as_ref().map(|_| "closure");
         f.debug_struct("SyncBuilder")
             .field("parallel_copies", &self.parallel_copies)
             .field("copy_contents_if_date_mismatched", &self.copy_contents_if_date_mismatched)
@@ -115,13 +338,67 @@ impl Debug for SyncBuilder {
             .field("copy_contents_if_contents_mismatched", &self.copy_contents_if_contents_mismatched)
             .field("copy_created_date", &self.copy_created_date)
             .field("copy_modified_date", &self.copy_modified_date)
+            .field("copy_permissions", &self.copy_permissions)
+            .field("copy_ownership", &self.copy_ownership)
+            .field("copy_xattrs", &self.copy_xattrs)
+            .field("preserve_hard_links", &self.preserve_hard_links)
             .field("directories", &self.directories)
             .field("filter", &filter_opt)
+            .field("metadata_filter", &self.metadata_filter)
+            .field("protect", &protect_opt)
+            .field("filter_rules", &self.filter_rules)
+            .field("filter_file_names", &self.filter_file_names)
+            .field("symlink_policy", &self.symlink_policy)
+            .field("archive_dir", &self.archive_dir)
+            .field("archive_keep_versions", &self.archive_keep_versions)
+            .field("archive_keep_days", &self.archive_keep_days)
+            .field("max_deleted_entries", &self.max_deleted_entries)
+            .field("max_deleted_percent", &self.max_deleted_percent)
+            .field("refuse_empty_source", &self.refuse_empty_source)
+            .field("marker_file", &self.marker_file)
+            .field("dry_run", &self.dry_run)
             .finish()
     }
 }
 
-#[derive(Debug)]
+/// What a sync does with a symlink in the source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Creates a symlink with the same target in the destination. The target isn't changed, so a
+    /// relative target points to the same place relative to the destination.
+    Copy,
+    /// Syncs what the symlink points to as if it were in the symlink's place. A symlink to a
+    /// directory that contains it is skipped so that the sync doesn't go around in a loop, and so
+    /// is one that points to nothing. Anything in the place of a skipped one is kept.
+    Follow,
+    /// Doesn't sync the symlink, and keeps anything in its place in the destination.
+    Skip,
+}
+
+impl fmt::Display for SymlinkPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            SymlinkPolicy::Copy => "copy",
+            SymlinkPolicy::Follow => "follow",
+            SymlinkPolicy::Skip => "skip",
+        })
+    }
+}
+
+impl FromStr for SymlinkPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "copy" => Ok(SymlinkPolicy::Copy),
+            "follow" => Ok(SymlinkPolicy::Follow),
+            "skip" => Ok(SymlinkPolicy::Skip),
+            _ => Err(format!("unknown symlink policy \"{}\"", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SyncLogLevel {
     Info,
     Debug,
@@ -132,13 +409,478 @@ pub enum SyncLogLevel {
 pub struct SyncLogEntry {
     pub time: Instant,
     pub level: SyncLogLevel,
+    /// A human readable rendering of `event`.
     pub message: String,
+    pub event: SyncEvent,
+}
+
+/// Something that happened during a sync.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyncEvent {
+    DirScanned {
+        src: PathBuf,
+        dest: PathBuf,
+    },
+    CopyStarted {
+        src: PathBuf,
+        dest: PathBuf,
+        reason: CopyReason,
+        size: u64,
+    },
+    CopyFinished {
+        src: PathBuf,
+        dest: PathBuf,
+        size: u64,
+    },
+    /// The operation was cancelled while copying, and the partially copied temporary file was
+    /// deleted. The destination is left as it was.
+    CopyCancelled {
+        src: PathBuf,
+        dest: PathBuf,
+    },
+    /// The permissions or owner of a file or directory that was otherwise up to date were changed
+    /// to match the source.
+    PermissionsCopied {
+        path: PathBuf,
+    },
+    /// The extended attributes of a file or directory that was otherwise up to date were changed
+    /// to match the source.
+    XattrsCopied {
+        path: PathBuf,
+    },
+    /// The file system of the destination directory of a pair doesn't support extended
+    /// attributes, so they aren't copied to it.
+    XattrsUnsupported {
+        dest: PathBuf,
+    },
+    /// A file was hard linked to `target`, which is the same file in the source.
+    HardLinked {
+        src: PathBuf,
+        dest: PathBuf,
+        target: PathBuf,
+    },
+    /// A symlink was created in the destination by `SymlinkPolicy::Copy`.
+    SymlinkCopied {
+        src: PathBuf,
+        dest: PathBuf,
+        target: PathBuf,
+    },
+    /// A file or directory was deleted. It follows `Archived` if it was moved to the archive.
+    Deleted {
+        path: PathBuf,
+        is_dir: bool,
+    },
+    /// A file or directory was moved to the archive instead of being deleted, or a file was copied
+    /// to the archive before being replaced.
+    Archived {
+        path: PathBuf,
+        archive_path: PathBuf,
+    },
+    /// An old version was deleted from the archive.
+    ArchivePruned {
+        path: PathBuf,
+    },
+    /// The deletion guard stopped the operation from deleting anything.
+    DeletionsRefused {
+        guard: DeletionGuard,
+    },
+    /// A directory pair wasn't synced because something is wrong with its directories.
+    PairRejected {
+        src: PathBuf,
+        dest: PathBuf,
+        problem: RootProblem,
+    },
+    Skipped {
+        path: PathBuf,
+        reason: SkipReason,
+    },
+    Error {
+        path: PathBuf,
+        op: SyncErrorOp,
+        kind: io::ErrorKind,
+        description: String,
+    },
+    ThreadExited {
+        worker: usize,
+    },
+}
+
+impl SyncEvent {
+    pub fn level(&self) -> SyncLogLevel {
+        match *self {
+            SyncEvent::Error { .. } |
+            SyncEvent::DeletionsRefused { .. } |
+            SyncEvent::PairRejected { .. } => SyncLogLevel::Error,
+            SyncEvent::DirScanned { .. } | SyncEvent::ThreadExited { .. } => SyncLogLevel::Debug,
+            _ => SyncLogLevel::Info,
+        }
+    }
+}
+
+impl fmt::Display for SyncEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SyncEvent::DirScanned { ref src, .. } =>
+                write!(f, "Scanned directory {}", src.to_string_lossy()),
+            SyncEvent::CopyStarted { ref src, reason, .. } =>
+                write!(f, "{:?}: Starting to copy {}", reason, src.to_string_lossy()),
+            SyncEvent::CopyFinished { ref src, .. } =>
+                write!(f, "Finished copying {}", src.to_string_lossy()),
+            SyncEvent::CopyCancelled { ref src, .. } =>
+                write!(f, "Cancelled copying {}", src.to_string_lossy()),
+            SyncEvent::PermissionsCopied { ref path } =>
+                write!(f, "Updated permissions of {}", path.to_string_lossy()),
+            SyncEvent::XattrsCopied { ref path } =>
+                write!(f, "Updated extended attributes of {}", path.to_string_lossy()),
+            SyncEvent::XattrsUnsupported { ref dest } =>
+                write!(f, "Not copying extended attributes to {} because its file system doesn't \
+                           support them", dest.to_string_lossy()),
+            SyncEvent::HardLinked { ref dest, ref target, .. } =>
+                write!(f, "Linked {} to {}", dest.to_string_lossy(), target.to_string_lossy()),
+            SyncEvent::SymlinkCopied { ref src, ref target, .. } =>
+                write!(f, "Copied symlink {} to {}", src.to_string_lossy(),
+                       target.to_string_lossy()),
+            SyncEvent::Deleted { ref path, is_dir: true } =>
+                write!(f, "Deleted directory {}", path.to_string_lossy()),
+            SyncEvent::Deleted { ref path, is_dir: false } =>
+                write!(f, "Deleted file {}", path.to_string_lossy()),
+            SyncEvent::Archived { ref path, ref archive_path } =>
+                write!(f, "Archived {} as {}", path.to_string_lossy(),
+                       archive_path.to_string_lossy()),
+            SyncEvent::ArchivePruned { ref path } =>
+                write!(f, "Deleted old archived version {}", path.to_string_lossy()),
+            SyncEvent::DeletionsRefused { guard: DeletionGuard::MaxEntries { entries, max } } =>
+                write!(f, "Refusing to delete {} files and directories, which is more than the \
+                           limit of {}", entries, max),
+            SyncEvent::DeletionsRefused {
+                guard: DeletionGuard::MaxPercent { entries, dest_entries, max_percent }
+            } =>
+                write!(f, "Refusing to delete {} of the {} files and directories in the \
+                           destination, which is more than {}%", entries, dest_entries, max_percent),
+            SyncEvent::PairRejected { ref src, ref dest, ref problem } =>
+                write!(f, "Not syncing {} to {} because {}", src.to_string_lossy(),
+                       dest.to_string_lossy(), problem),
+            SyncEvent::DeletionsRefused { guard: DeletionGuard::EmptySource(ref path) } =>
+                write!(f, "Refusing to delete anything because the source directory {} is empty \
+                           or missing", path.to_string_lossy()),
+            SyncEvent::Skipped { ref path, reason: SkipReason::Filter } =>
+                write!(f, "Skipping file {}", path.to_string_lossy()),
+            SyncEvent::Skipped { ref path, reason: SkipReason::Symlink } =>
+                write!(f, "Skipping symlink {}", path.to_string_lossy()),
+            SyncEvent::Skipped { ref path, reason: SkipReason::SymlinkLoop } =>
+                write!(f, "Skipping symlink {} because it points to a directory containing it",
+                       path.to_string_lossy()),
+            SyncEvent::Skipped { ref path, reason: SkipReason::Protected } =>
+                write!(f, "Keeping protected file {}", path.to_string_lossy()),
+            SyncEvent::Error { ref path, op, ref description, .. } =>
+                write!(f, "Failed to {} {}: {}", op, path.to_string_lossy(), description),
+            SyncEvent::ThreadExited { worker } =>
+                write!(f, "Thread {} exiting", worker),
+        }
+    }
+}
+
+/// What is wrong with the directories of a pair that wasn't synced.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RootProblem {
+    /// The directory doesn't exist or its information couldn't be read.
+    Missing(PathBuf),
+    NotDirectory(PathBuf),
+    /// The source and destination are the same directory.
+    SamePath,
+    /// One of the directories is inside the other.
+    Nested,
+    /// The marker file set with `SyncBuilder::marker_file()` isn't in the directory.
+    MarkerMissing(PathBuf),
+}
+
+impl fmt::Display for RootProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RootProblem::Missing(ref path) =>
+                write!(f, "{} does not exist or can't be read", path.to_string_lossy()),
+            RootProblem::NotDirectory(ref path) =>
+                write!(f, "{} is not a directory", path.to_string_lossy()),
+            RootProblem::SamePath =>
+                write!(f, "the source and destination are the same directory"),
+            RootProblem::Nested =>
+                write!(f, "one directory is inside the other"),
+            RootProblem::MarkerMissing(ref path) =>
+                write!(f, "the marker file {} is missing", path.to_string_lossy()),
+        }
+    }
+}
+
+/// Which limit of the deletion guard would have been exceeded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeletionGuard {
+    MaxEntries {
+        entries: u64,
+        max: u64,
+    },
+    MaxPercent {
+        entries: u64,
+        dest_entries: u64,
+        max_percent: u8,
+    },
+    EmptySource(PathBuf),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkipReason {
+    /// The filter returned false for the path.
+    Filter,
+    /// The path is a symlink in the source that isn't synced because of the symlink policy or
+    /// because it points to something that doesn't exist.
+    Symlink,
+    /// The path is a symlink to a directory that contains it, so following it would never end.
+    SymlinkLoop,
+    /// The path is in the destination and protected from being deleted.
+    Protected,
+}
+
+/// The operation that failed in a `SyncEvent::Error`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncErrorOp {
+    ListDir,
+    ReadDirEntry,
+    ReadMetadata,
+    Open,
+    Read,
+    Seek,
+    Copy,
+    Flush,
+    Rename,
+    DeleteFile,
+    DeleteDir,
+    DeletePartial,
+    GetCreated,
+    SetCreated,
+    GetModified,
+    SetModified,
+    SetPermissions,
+    SetOwner,
+    ReadXattrs,
+    SetXattrs,
+    Archive,
+    CreateDir,
+    ReadLink,
+    CreateSymlink,
+    /// A bug in mirror-sync caused a panic while syncing the path.
+    Sync,
+    /// The directory pair starting at the source path was rejected.
+    SyncPair,
+    /// The deletion guard refused to delete anything. The path is empty unless the source was
+    /// empty, since the other limits apply to the whole operation.
+    DeleteExtra,
+}
+
+impl fmt::Display for SyncErrorOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            SyncErrorOp::ListDir => "get the list of files in",
+            SyncErrorOp::ReadDirEntry => "read the name of a file in",
+            SyncErrorOp::ReadMetadata => "read information about",
+            SyncErrorOp::Open => "open",
+            SyncErrorOp::Read => "read",
+            SyncErrorOp::Seek => "seek",
+            SyncErrorOp::Copy => "copy",
+            SyncErrorOp::Flush => "flush",
+            SyncErrorOp::Rename => "replace",
+            SyncErrorOp::DeleteFile => "delete file",
+            SyncErrorOp::DeleteDir => "delete directory",
+            SyncErrorOp::DeletePartial => "delete partially copied file",
+            SyncErrorOp::GetCreated => "get created date of",
+            SyncErrorOp::SetCreated => "set created date of",
+            SyncErrorOp::GetModified => "get modified date of",
+            SyncErrorOp::SetModified => "set modified date of",
+            SyncErrorOp::SetPermissions => "set permissions of",
+            SyncErrorOp::SetOwner => "set owner of",
+            SyncErrorOp::ReadXattrs => "read extended attributes of",
+            SyncErrorOp::SetXattrs => "set extended attributes of",
+            SyncErrorOp::Archive => "move to archive",
+            SyncErrorOp::CreateDir => "create directory",
+            SyncErrorOp::ReadLink => "read the target of symlink",
+            SyncErrorOp::CreateSymlink => "create symlink",
+            SyncErrorOp::Sync => "sync",
+            SyncErrorOp::SyncPair => "sync directory",
+            SyncErrorOp::DeleteExtra => "delete extra files and directories in",
+        })
+    }
+}
+
+/// An operation that a dry run found would be performed by a sync.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PlannedOperation {
+    CreateDir(PathBuf),
+    CopyFile {
+        src: PathBuf,
+        dest: PathBuf,
+        reason: CopyReason,
+    },
+    CopySymlink {
+        src: PathBuf,
+        dest: PathBuf,
+        target: PathBuf,
+    },
+    HardLink {
+        src: PathBuf,
+        dest: PathBuf,
+        target: PathBuf,
+    },
+    /// The permissions or owner of a file or directory that is otherwise up to date.
+    SetPermissions(PathBuf),
+    /// The extended attributes of a file or directory that is otherwise up to date.
+    SetXattrs(PathBuf),
+    DeleteFile(PathBuf),
+    DeleteDirAll(PathBuf),
+}
+
+/// A snapshot of how far along a sync is.
+#[derive(Debug, Clone)]
+pub struct SyncProgress {
+    pub dirs_scanned: u64,
+    pub files_compared: u64,
+    pub files_copied: u64,
+    /// Files that were already up to date, were skipped by the filter or a symlink, or were
+    /// protected from being deleted.
+    pub files_skipped: u64,
+    pub files_deleted: u64,
+    pub dirs_deleted: u64,
+    /// Files and directories moved or copied to the archive.
+    pub files_archived: u64,
+    pub bytes_copied: u64,
+    /// The bytes in the holes of sparse files, which were skipped instead of being copied.
+    pub sparse_bytes_skipped: u64,
+    /// The size of the files waiting in the queue to be compared and copied if needed.
+    pub bytes_remaining: u64,
+    pub errors: u64,
+    /// The file or directory each thread is currently working on.
+    pub in_flight: Vec<Option<PathBuf>>,
+    pub elapsed: Duration,
+    /// An estimate of the time left based on the copy speed so far. `None` until some bytes
+    /// have been copied. It assumes every remaining file will be copied, so it may be too high.
+    pub eta: Option<Duration>,
+}
+
+/// A summary of a finished operation.
+#[derive(Debug, Clone)]
+pub struct SyncReport {
+    /// True if the operation was cancelled before it finished.
+    pub cancelled: bool,
+    pub dirs_scanned: u64,
+    pub files_compared: u64,
+    pub files_copied: u64,
+    pub files_skipped: u64,
+    pub files_deleted: u64,
+    pub dirs_deleted: u64,
+    pub files_archived: u64,
+    pub bytes_copied: u64,
+    pub sparse_bytes_skipped: u64,
+    pub errors: u64,
+    pub duration: Duration,
+    /// The average number of bytes copied per second.
+    pub bytes_per_sec: f64,
+    /// The results of each directory pair, in the order they were added.
+    pub pairs: Vec<PairReport>,
+    /// Every error that happened, in the order they happened.
+    pub failures: Vec<SyncFailure>,
+    /// Set if the deletion guard stopped the operation from deleting anything.
+    pub deletions_refused: Option<DeletionGuard>,
+}
+
+/// The results of one directory pair in a `SyncReport`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PairReport {
+    pub src: PathBuf,
+    pub dest: PathBuf,
+    /// Set if the pair wasn't synced at all.
+    pub rejected: Option<RootProblem>,
+    pub files_copied: u64,
+    pub bytes_copied: u64,
+    pub files_deleted: u64,
+    pub dirs_deleted: u64,
+    pub files_archived: u64,
+    pub errors: u64,
+}
+
+/// An error in a `SyncReport`. It has the same information as a `SyncEvent::Error`, or describes a
+/// `SyncEvent::PairRejected` or `SyncEvent::DeletionsRefused` with `SyncErrorOp::SyncPair` or
+/// `SyncErrorOp::DeleteExtra`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyncFailure {
+    pub path: PathBuf,
+    pub op: SyncErrorOp,
+    pub kind: io::ErrorKind,
+    pub description: String,
+}
+
+#[derive(Debug)]
+struct ProgressData {
+    start_time: Option<Instant>,
+    end_time: Option<Instant>,
+    dirs_scanned: u64,
+    files_compared: u64,
+    files_copied: u64,
+    files_skipped: u64,
+    files_deleted: u64,
+    dirs_deleted: u64,
+    files_archived: u64,
+    bytes_copied: u64,
+    sparse_bytes_skipped: u64,
+    bytes_remaining: u64,
+    errors: u64,
+    in_flight: Vec<Option<PathBuf>>,
+}
+
+// The parts of the report that are collected from the events.
+#[derive(Debug)]
+struct ReportData {
+    pairs: Vec<PairReport>,
+    failures: Vec<SyncFailure>,
+    deletions_refused: Option<DeletionGuard>,
+}
+
+// The callbacks registered with `on_complete()`.
+struct CompleteData {
+    callbacks: Vec<Box<FnMut(&SyncReport) + Send>>,
+    // Set once the callbacks have been called, after which new ones are called immediately.
+    completed: bool,
+}
+
+#[derive(Debug)]
+struct ControlData {
+    paused: bool,
+    cancelled: bool,
 }
 
 #[derive(Debug)]
 struct DoneData {
     waiting_count: u8,
     done: bool,
+    // Set once the deletions held back for the deletion guard have been checked.
+    deletions_checked: bool,
+}
+
+struct HardLinks {
+    // The destination path of the first file found with each device and inode in the source.
+    targets: HashMap<(u64, u64), PathBuf>,
+    // The other files with the same device and inode, which are linked at the end.
+    pending: Vec<IoOperation>,
+}
+
+// Deletions held back until the deletion guard checks them.
+struct DeletionData {
+    ops: Vec<IoOperation>,
+    // Directories in the source whose place in the destination is taken by a file. They are
+    // synced once the file is allowed to be deleted.
+    dirs: Vec<(PathBuf, PathBuf, Option<Arc<DirFilters>>, Option<Arc<AncestorDirs>>)>,
+    // Set once the guard has allowed the deletions, after which they aren't held back.
+    allowed: bool,
+    // The number of files and directories that the held back operations would delete.
+    entries: u64,
+    // The number of files and directories in the destination.
+    dest_entries: u64,
 }
 
 struct SyncOperationData {
@@ -148,12 +890,40 @@ struct SyncOperationData {
     // I know it wouldn't with my primary usecase of copying across a network, but maybe
     // it does SSD to SSD.
     log_queue: SegQueue<SyncLogEntry>,
-    sync_dir_queue: SegQueue<(PathBuf, PathBuf)>,
+    // When set by `log_receiver()`, log entries are sent here instead of to `log_queue`.
+    log_sender: Mutex<Option<mpsc::Sender<SyncLogEntry>>>,
+    plan_queue: SegQueue<PlannedOperation>,
+    sync_dir_queue: SegQueue<(PathBuf, PathBuf, Option<Arc<DirFilters>>,
+                              Option<Arc<AncestorDirs>>)>,
     op_queue: SegQueue<IoOperation>,
 
     done_data: Mutex<DoneData>,
     done_condvar: Condvar,
-    // errors
+    // Notified when the operation is done. The threads wait on `done_condvar` for more work, so
+    // callers of `wait()` use a separate condition variable to not take their notifications.
+    finished_condvar: Condvar,
+
+    control_data: Mutex<ControlData>,
+    control_condvar: Condvar,
+
+    progress_data: Mutex<ProgressData>,
+
+    deletion_data: Mutex<DeletionData>,
+
+    report_data: Mutex<ReportData>,
+
+    complete_data: Mutex<CompleteData>,
+
+    // Directories in the destination whose permissions and extended attributes are set after
+    // everything else is done, so that a read-only directory can still be copied into.
+    dir_attributes: Mutex<Vec<DirAttributes>>,
+    hard_links: Mutex<HardLinks>,
+
+    // Set for each directory pair whose destination doesn't support extended attributes.
+    xattrs_unsupported: Mutex<Vec<bool>>,
+
+    // The time used to name versions put in the archive.
+    archive_time: SystemTime,
 }
 
 #[derive(Clone)]
@@ -164,13 +934,71 @@ impl SyncOperation {
         SyncOperation(Arc::new(SyncOperationData {
             options: sync_builder.clone(),
             log_queue: SegQueue::new(),
+            log_sender: Mutex::new(None),
+            plan_queue: SegQueue::new(),
             sync_dir_queue: SegQueue::new(),
             op_queue: SegQueue::new(),
             done_data: Mutex::new(DoneData {
                 waiting_count: 0,
                 done: false,
+                deletions_checked: false,
             }),
             done_condvar: Condvar::new(),
+            finished_condvar: Condvar::new(),
+            control_data: Mutex::new(ControlData {
+                paused: false,
+                cancelled: false,
+            }),
+            control_condvar: Condvar::new(),
+            progress_data: Mutex::new(ProgressData {
+                start_time: None,
+                end_time: None,
+                dirs_scanned: 0,
+                files_compared: 0,
+                files_copied: 0,
+                files_skipped: 0,
+                files_deleted: 0,
+                dirs_deleted: 0,
+                files_archived: 0,
+                bytes_copied: 0,
+                sparse_bytes_skipped: 0,
+                bytes_remaining: 0,
+                errors: 0,
+                in_flight: vec![None; sync_builder.parallel_copies as usize],
+            }),
+            deletion_data: Mutex::new(DeletionData {
+                ops: vec![],
+                dirs: vec![],
+                allowed: false,
+                entries: 0,
+                dest_entries: 0,
+            }),
+            report_data: Mutex::new(ReportData {
+                pairs: sync_builder.directories.iter().map(|&(ref src, ref dest)| PairReport {
+                    src: src.clone(),
+                    dest: dest.clone(),
+                    rejected: None,
+                    files_copied: 0,
+                    bytes_copied: 0,
+                    files_deleted: 0,
+                    dirs_deleted: 0,
+                    files_archived: 0,
+                    errors: 0,
+                }).collect(),
+                failures: vec![],
+                deletions_refused: None,
+            }),
+            complete_data: Mutex::new(CompleteData {
+                callbacks: vec![],
+                completed: false,
+            }),
+            dir_attributes: Mutex::new(vec![]),
+            hard_links: Mutex::new(HardLinks {
+                targets: HashMap::new(),
+                pending: vec![],
+            }),
+            xattrs_unsupported: Mutex::new(vec![false; sync_builder.directories.len()]),
+            archive_time: SystemTime::now(),
         }))
     }
 
@@ -179,68 +1007,340 @@ impl SyncOperation {
         done_data.done
     }
 
+    /// Blocks until the operation is done and returns its report.
+    pub fn wait(&self) -> SyncReport {
+        let mut done_data = self.0.done_data.lock().unwrap();
+        while !done_data.done {
+            done_data = self.0.finished_condvar.wait(done_data).unwrap();
+        }
+        drop(done_data);
+        self.build_report()
+    }
+
+    /// Blocks until the operation is done or the timeout passes. Returns the report if the
+    /// operation is done, or `None` if it is still running.
+    pub fn wait_timeout(&self, timeout: Duration) -> Option<SyncReport> {
+        let deadline = Instant::now() + timeout;
+        let mut done_data = self.0.done_data.lock().unwrap();
+        while !done_data.done {
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            done_data = self.0.finished_condvar.wait_timeout(done_data, deadline - now).unwrap().0;
+        }
+        drop(done_data);
+        Some(self.build_report())
+    }
+
+    /// Registers a function to call with the report when the operation is done. It is called on
+    /// the operation's thread, or immediately on this thread if the operation is already done.
+    pub fn on_complete<F: FnOnce(&SyncReport) + Send + 'static>(&self, f: F) {
+        let mut f = Some(f);
+        let mut complete_data = self.0.complete_data.lock().unwrap();
+        if complete_data.completed {
+            drop(complete_data);
+            f.take().unwrap()(&self.build_report());
+        } else {
+            complete_data.callbacks.push(Box::new(move |report: &SyncReport| {
+                if let Some(f) = f.take() {
+                    f(report);
+                }
+            }));
+        }
+    }
+
+    /// Returns the report if the operation is done, or `None` if it is still running.
+    pub fn report(&self) -> Option<SyncReport> {
+        if self.is_done() {
+            Some(self.build_report())
+        } else {
+            None
+        }
+    }
+
+    fn build_report(&self) -> SyncReport {
+        let progress = self.progress();
+        let report_data = self.0.report_data.lock().unwrap();
+        let secs = progress.elapsed.as_secs_f64();
+        SyncReport {
+            cancelled: self.is_cancelled(),
+            dirs_scanned: progress.dirs_scanned,
+            files_compared: progress.files_compared,
+            files_copied: progress.files_copied,
+            files_skipped: progress.files_skipped,
+            files_deleted: progress.files_deleted,
+            dirs_deleted: progress.dirs_deleted,
+            files_archived: progress.files_archived,
+            bytes_copied: progress.bytes_copied,
+            sparse_bytes_skipped: progress.sparse_bytes_skipped,
+            errors: progress.errors,
+            duration: progress.elapsed,
+            bytes_per_sec: if secs > 0.0 { progress.bytes_copied as f64 / secs } else { 0.0 },
+            pairs: report_data.pairs.clone(),
+            failures: report_data.failures.clone(),
+            deletions_refused: report_data.deletions_refused.clone(),
+        }
+    }
+
+    /// Stops the operation as soon as possible. Threads finish or undo the operation they are
+    /// working on and then exit without starting any more. A file that is partially copied is
+    /// left unchanged. `is_done()` returns true once all threads have exited.
+    pub fn cancel(&self) {
+        {
+            let mut control_data = self.0.control_data.lock().unwrap();
+            control_data.cancelled = true;
+            control_data.paused = false;
+            self.0.control_condvar.notify_all();
+        }
+        // Wake up threads waiting for work so that they exit. The lock is held so that a thread
+        // can't miss the notification between checking for cancellation and waiting.
+        let _done_data = self.0.done_data.lock().unwrap();
+        self.0.done_condvar.notify_all();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        let control_data = self.0.control_data.lock().unwrap();
+        control_data.cancelled
+    }
+
+    /// Pauses the operation. Threads stop before starting their next operation or before copying
+    /// the next chunk of a file until `resume()` is called.
+    pub fn pause(&self) {
+        let mut control_data = self.0.control_data.lock().unwrap();
+        if !control_data.cancelled {
+            control_data.paused = true;
+        }
+    }
+
+    pub fn resume(&self) {
+        let mut control_data = self.0.control_data.lock().unwrap();
+        control_data.paused = false;
+        self.0.control_condvar.notify_all();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        let control_data = self.0.control_data.lock().unwrap();
+        control_data.paused
+    }
+
+    pub fn progress(&self) -> SyncProgress {
+        let progress_data = self.0.progress_data.lock().unwrap();
+        let elapsed = match (progress_data.start_time, progress_data.end_time) {
+            (Some(start_time), Some(end_time)) => end_time.duration_since(start_time),
+            (Some(start_time), None) => start_time.elapsed(),
+            (None, _) => Duration::from_secs(0),
+        };
+        let eta = estimate_eta(progress_data.bytes_copied, progress_data.bytes_remaining, elapsed);
+        SyncProgress {
+            dirs_scanned: progress_data.dirs_scanned,
+            files_compared: progress_data.files_compared,
+            files_copied: progress_data.files_copied,
+            files_skipped: progress_data.files_skipped,
+            files_deleted: progress_data.files_deleted,
+            dirs_deleted: progress_data.dirs_deleted,
+            files_archived: progress_data.files_archived,
+            bytes_copied: progress_data.bytes_copied,
+            sparse_bytes_skipped: progress_data.sparse_bytes_skipped,
+            bytes_remaining: progress_data.bytes_remaining,
+            errors: progress_data.errors,
+            in_flight: progress_data.in_flight.clone(),
+            elapsed,
+            eta,
+        }
+    }
+
     pub fn read_log(&self) -> Option<SyncLogEntry> {
         self.0.log_queue.try_pop()
     }
 
+    /// Returns a channel that receives the log entries instead of `read_log()`, starting with any
+    /// that haven't been read yet. The channel is closed when the operation is done, so iterating
+    /// over the receiver blocks until the operation is done. Calling it again replaces the
+    /// previous channel.
+    pub fn log_receiver(&self) -> mpsc::Receiver<SyncLogEntry> {
+        let (sender, receiver) = mpsc::channel();
+        let mut log_sender = self.0.log_sender.lock().unwrap();
+        while let Some(entry) = self.0.log_queue.try_pop() {
+            let _ = sender.send(entry);
+        }
+        // After the operation is done, nothing else will be logged.
+        if !self.0.complete_data.lock().unwrap().completed {
+            *log_sender = Some(sender);
+        }
+        receiver
+    }
+
+    /// Returns the next operation found by a dry run started with `SyncBuilder::plan()`. Always
+    /// returns `None` for an operation started with `SyncBuilder::sync()`.
+    pub fn read_plan(&self) -> Option<PlannedOperation> {
+        self.0.plan_queue.try_pop()
+    }
+
     fn run(&self) {
+        self.update_progress(|p| p.start_time = Some(Instant::now()));
+        if let Some(ref archive_dir) = self.0.options.archive_dir {
+            if self.0.options.archive_keep_days > 0 && !self.0.options.dry_run {
+                let keep_secs = self.0.options.archive_keep_days as u64 * 24 * 60 * 60;
+                // If the cutoff is before the time can go, nothing is old enough to be pruned.
+                let cutoff = self.0.archive_time.checked_sub(Duration::from_secs(keep_secs));
+                if let Some(cutoff) = cutoff {
+                    self.prune_archive(archive_dir, cutoff);
+                }
+            }
+        }
         for &(ref src, ref dest) in &self.0.options.directories {
-            self.0.sync_dir_queue.push((src.into(), dest.into()));
+            match self.check_roots(src, dest) {
+                Ok(()) => {
+                    let dir_filters = if self.0.options.filter_rules.is_empty() {
+                        None
+                    } else {
+                        Some(Arc::new(DirFilters {
+                            parent: None,
+                            dir: src.into(),
+                            rules: self.0.options.filter_rules.clone(),
+                        }))
+                    };
+                    self.0.sync_dir_queue.push((src.into(), dest.into(), dir_filters, None));
+                },
+                Err(problem) => self.log(SyncEvent::PairRejected {
+                    src: src.into(),
+                    dest: dest.into(),
+                    problem,
+                }),
+            }
         }
 
         // TODO: normally, I much prefer using thread pools, but you can create 10 threads in 0.3 ms,
         // so it is a drop in the bucket compared to the file operations.
         crossbeam::scope(|scope| {
-            for _ in 0..self.0.options.parallel_copies {
-                scope.spawn(|| {
-                    self.sync_thread();
+            for worker in 0..self.0.options.parallel_copies as usize {
+                scope.spawn(move || {
+                    self.sync_thread(worker);
                 });
             }
         });
+
+        // When cancelled, threads exit without the last one marking the operation done.
+        {
+            let mut done_data = self.0.done_data.lock().unwrap();
+            if !done_data.done {
+                self.finish(&mut done_data);
+            }
+        }
+
+        // Every thread has exited, so nothing else will be logged.
+        let callbacks = {
+            let mut log_sender = self.0.log_sender.lock().unwrap();
+            *log_sender = None;
+            let mut complete_data = self.0.complete_data.lock().unwrap();
+            complete_data.completed = true;
+            mem::replace(&mut complete_data.callbacks, vec![])
+        };
+        if !callbacks.is_empty() {
+            let report = self.build_report();
+            for mut callback in callbacks {
+                callback(&report);
+            }
+        }
+    }
+
+    // Marks the operation done. The done lock must be held.
+    fn finish(&self, done_data: &mut DoneData) {
+        self.update_progress(|p| p.end_time = Some(Instant::now()));
+        done_data.done = true;
+        self.0.done_condvar.notify_all();
+        self.0.finished_condvar.notify_all();
+    }
+
+    // Checks that the directories of a pair are safe to sync.
+    fn check_roots(&self, src: &Path, dest: &Path) -> Result<(), RootProblem> {
+        for dir in &[src, dest] {
+            match fs::metadata(dir) {
+                Ok(ref meta) if meta.is_dir() => {},
+                Ok(_) => return Err(RootProblem::NotDirectory(dir.into())),
+                Err(_) => return Err(RootProblem::Missing(dir.into())),
+            }
+        }
+        let src_canonical = src.canonicalize().map_err(|_| RootProblem::Missing(src.into()))?;
+        let dest_canonical = dest.canonicalize().map_err(|_| RootProblem::Missing(dest.into()))?;
+        if src_canonical == dest_canonical {
+            return Err(RootProblem::SamePath);
+        }
+        if src_canonical.starts_with(&dest_canonical) || dest_canonical.starts_with(&src_canonical) {
+            return Err(RootProblem::Nested);
+        }
+        if let Some(ref marker_file) = self.0.options.marker_file {
+            for dir in &[src, dest] {
+                let marker_path = dir.join(marker_file);
+                if fs::metadata(&marker_path).is_err() {
+                    return Err(RootProblem::MarkerMissing(marker_path));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Blocks while the operation is paused. Returns false if the operation has been cancelled.
+    fn wait_if_paused(&self) -> bool {
+        let mut control_data = self.0.control_data.lock().unwrap();
+        while control_data.paused {
+            control_data = self.0.control_condvar.wait(control_data).unwrap();
+        }
+        !control_data.cancelled
     }
 
-    fn sync_thread(&self) {
+    fn sync_thread(&self, worker: usize) {
         loop {
+            if !self.wait_if_paused() {
+                self.log(SyncEvent::ThreadExited { worker });
+                break;
+            }
             if let Some(op) = self.0.op_queue.try_pop() {
-                match op {
-                    IoOperation::CopyFileIfNeeded(data) => {
-                        self.copy_file_if_needed(data);
-                    },
-                    IoOperation::DeleteDirAll(ref dir) => {
-                        if let Err(err) = fs::remove_dir_all(dir) {
-                            self.log(SyncLogLevel::Error,
-                                     format!("Failed to delete directory {}: {}",
-                                     dir.to_string_lossy(), err.description()));
-                        } else {
-                            self.log(SyncLogLevel::Info,
-                                    format!("Deleted directory {}",
-                                    dir.to_string_lossy()));
-                        }
+                let in_flight = match op {
+                    IoOperation::CopyFileIfNeeded(ref data) => data.src.clone(),
+                    IoOperation::CopySymlink(ref data) => data.src.clone(),
+                    IoOperation::HardLink(ref data) => data.src.clone(),
+                    IoOperation::DeleteDirAll(ref path) | IoOperation::DeleteFile(ref path) => {
+                        path.clone()
                     },
-                    IoOperation::DeleteFile(ref file) => {
-                        if let Err(err) = fs::remove_file(file) {
-                            self.log(SyncLogLevel::Error,
-                                     format!("Failed to delete file {}: {}",
-                                     file.to_string_lossy(), err.description()));
-                        } else {
-                            self.log(SyncLogLevel::Info,
-                                    format!("Deleted file {}",
-                                    file.to_string_lossy()));
-                        }
-                    },
-                }
-            } else if let Some((src, dest)) = self.0.sync_dir_queue.try_pop() {
-                self.sync_dir(&src, &dest);
+                };
+                self.update_progress(|p| p.in_flight[worker] = Some(in_flight.clone()));
+                self.catch_panic(&in_flight, || {
+                    if self.0.options.dry_run {
+                        self.plan_operation(op);
+                    } else {
+                        self.perform_operation(op);
+                    }
+                });
+                self.update_progress(|p| p.in_flight[worker] = None);
+            } else if let Some((src, dest, dir_filters, ancestors)) =
+                          self.0.sync_dir_queue.try_pop() {
+                self.update_progress(|p| p.in_flight[worker] = Some(src.clone()));
+                self.catch_panic(&src, || self.sync_dir(&src, &dest, dir_filters, ancestors));
+                self.update_progress(|p| p.in_flight[worker] = None);
             } else {
                 let mut done_data = self.0.done_data.lock().unwrap();
-                if done_data.done {
-                    self.log(SyncLogLevel::Debug, "Thread exiting"); // TODO: number?
+                if done_data.done || self.is_cancelled() {
+                    self.log(SyncEvent::ThreadExited { worker });
                     break;
                 }
                 if done_data.waiting_count == self.0.options.parallel_copies - 1 {
-                    done_data.done = true;
-                    self.0.done_condvar.notify_all();
-                    self.log(SyncLogLevel::Debug, "Thread exiting"); // TODO: number?
+                    // Everything but the deletions is finished, so check them before finishing.
+                    if !done_data.deletions_checked && self.0.options.has_deletion_guard() {
+                        done_data.deletions_checked = true;
+                        self.check_deletions();
+                        continue;
+                    }
+                    // Hard links found after the last ones were queued, like in a directory
+                    // released by the deletion guard, are queued the next time everything else is
+                    // finished.
+                    if self.queue_hard_links() {
+                        continue;
+                    }
+                    self.copy_dir_attributes();
+                    self.finish(&mut done_data);
+                    self.log(SyncEvent::ThreadExited { worker });
                     break;
                 }
                 done_data.waiting_count += 1;
@@ -250,90 +1350,707 @@ impl SyncOperation {
         }
     }
 
-    fn log<S: Into<String>>(&self, level: SyncLogLevel, message: S) {
-        self.0.log_queue.push(SyncLogEntry {
-            time: Instant::now(),
-            level: level,
-            message: message.into(),
-        });
-    }
-
-    fn add_to_sync_dir_queue(&self, src: PathBuf, dest: PathBuf) {
-        self.0.sync_dir_queue.push((src, dest));
-        self.0.done_condvar.notify_one();
-    }
-
-    fn add_to_op_queue(&self, op: IoOperation) {
-        self.0.op_queue.push(op);
-        self.0.done_condvar.notify_one();
+    // Runs the function, reporting a panic as an error instead of letting it kill the thread, which
+    // would leave the other threads waiting forever for it to finish.
+    fn catch_panic<F: FnOnce()>(&self, path: &Path, f: F) {
+        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(f)) {
+            let message = payload.downcast_ref::<&str>().map(|s| s.to_string())
+                                 .or_else(|| payload.downcast_ref::<String>().cloned())
+                                 .unwrap_or_else(|| "unknown panic".into());
+            self.log(SyncEvent::Error {
+                path: path.into(),
+                op: SyncErrorOp::Sync,
+                kind: io::ErrorKind::Other,
+                description: format!("internal error: {}", message),
+            });
+        }
     }
 
-    fn sync_dir(&self, src_dir: &Path, dest_dir: &Path) {
-        // If the directory is a file or it doesn't exist, create it.
-        let dest_meta = fs::symlink_metadata(&dest_dir); // TODO: should follow symlinks?
-        match dest_meta {
-            Ok(metadata) => {
-                if !metadata.is_dir() {
-                    fs::remove_file(&dest_dir);
-                    fs::create_dir(&dest_dir);
+    fn perform_operation(&self, op: IoOperation) {
+        match op {
+            IoOperation::CopyFileIfNeeded(data) => {
+                self.copy_file_if_needed(data);
+            },
+            IoOperation::CopySymlink(data) => {
+                self.copy_symlink(data);
+            },
+            IoOperation::HardLink(data) => {
+                self.hard_link_file(data);
+            },
+            IoOperation::DeleteDirAll(ref dir) if self.0.options.archive_dir.is_some() => {
+                if self.archive(dir, true) {
+                    self.log(SyncEvent::Deleted { path: dir.clone(), is_dir: true });
+                    self.update_progress(|p| p.dirs_deleted += 1);
                 }
             },
-            Err(err) => {
-                if err.kind() == io::ErrorKind::NotFound {
-                    fs::create_dir(&dest_dir);
+            IoOperation::DeleteFile(ref file) if self.0.options.archive_dir.is_some() => {
+                if self.archive(file, true) {
+                    self.log(SyncEvent::Deleted { path: file.clone(), is_dir: false });
+                    self.update_progress(|p| p.files_deleted += 1);
                 }
-            }
+            },
+            IoOperation::DeleteDirAll(ref dir) => {
+                if let Err(err) = fs::remove_dir_all(dir) {
+                    self.log_error(SyncErrorOp::DeleteDir, &dir, &err);
+                } else {
+                    self.log(SyncEvent::Deleted { path: dir.clone(), is_dir: true });
+                    self.update_progress(|p| p.dirs_deleted += 1);
+                }
+            },
+            IoOperation::DeleteFile(ref file) => {
+                if let Err(err) = remove_file_or_link(file) {
+                    self.log_error(SyncErrorOp::DeleteFile, &file, &err);
+                } else {
+                    self.log(SyncEvent::Deleted { path: file.clone(), is_dir: false });
+                    self.update_progress(|p| p.files_deleted += 1);
+                }
+            },
         }
+    }
 
-        // List the destination directory.
-        let dest_entries = match fs::read_dir(dest_dir) {
-            Ok(entries) => entries,
-            Err(err) => {
-                self.log(SyncLogLevel::Error,
-                         format!("Failed to get the list of files in {}: {}",
-                         dest_dir.to_string_lossy(), err.description()));
-                return;
+    // Moves a file or directory that is being deleted to the archive, or copies a file that is
+    // about to be replaced. Returns true if it was archived.
+    fn archive(&self, path: &Path, remove: bool) -> bool {
+        let archive_path = match self.archive_path(path) {
+            Some(archive_path) => archive_path,
+            None => {
+                let err = io::Error::new(io::ErrorKind::InvalidInput,
+                                         "the path is not in a destination directory");
+                self.log_error(SyncErrorOp::Archive, path, &err);
+                return false;
             },
         };
-        let (mut dest_entries, read_dir_errors): (HashMap<_, _>, Vec<_>) = dest_entries
-                                                                           .partition_map(|res|
-            match res {
-                Ok(entry) => Partition::Left((entry.path(), entry)),
-                Err(err) => Partition::Right(err),
+        let result = if remove {
+            archive::move_to_archive(path, &archive_path)
+        } else {
+            archive::copy_to_archive(path, &archive_path)
+        };
+        // A number is added to the name if a version was already archived in the same second.
+        let archive_path = match result {
+            Ok(archive_path) => archive_path,
+            Err(err) => {
+                self.log_error(SyncErrorOp::Archive, path, &err);
+                return false;
+            },
+        };
+        self.log(SyncEvent::Archived { path: path.into(), archive_path: archive_path.clone() });
+        self.update_progress(|p| p.files_archived += 1);
+        if self.0.options.archive_keep_versions > 0 {
+            self.prune_versions(&archive_path);
+        }
+        true
+    }
+
+    // Returns the path in the archive for a file or directory in a destination directory.
+    fn archive_path(&self, path: &Path) -> Option<PathBuf> {
+        let archive_dir = match self.0.options.archive_dir {
+            Some(ref archive_dir) => archive_dir,
+            None => return None,
+        };
+        // Use the innermost destination directory in case one is inside another.
+        let dest_dir = self.0.options.directories.iter()
+                           .map(|&(_, ref dest)| dest)
+                           .filter(|dest| path.starts_with(dest))
+                           .max_by_key(|dest| dest.components().count());
+        let rel_path = match dest_dir {
+            Some(dest_dir) => path.strip_prefix(dest_dir.parent().unwrap_or(dest_dir)).ok(),
+            None => None,
+        };
+        match (rel_path, path.file_name()) {
+            (Some(rel_path), Some(name)) => {
+                let version_name = archive::version_name(name, self.0.archive_time);
+                Some(archive_dir.join(rel_path).with_file_name(version_name))
+            },
+            _ => None,
+        }
+    }
+
+    // Deletes the oldest versions of an archived file beyond the number to keep.
+    fn prune_versions(&self, archive_path: &Path) {
+        let (archive_dir, name) = match (archive_path.parent(), archive_path.file_name()) {
+            (Some(archive_dir), Some(version_name)) => {
+                match archive::parse_version_name(version_name) {
+                    Some((name, _, _)) => (archive_dir, name.to_owned()),
+                    None => return,
+                }
+            },
+            _ => return,
+        };
+        let entries = match fs::read_dir(archive_dir) {
+            Ok(entries) => entries,
+            Err(err) => {
+                self.log_error(SyncErrorOp::ListDir, archive_dir, &err);
+                return;
+            },
+        };
+        let mut versions = vec![];
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let file_name = entry.file_name();
+            let version = match archive::parse_version_name(&file_name) {
+                Some((version_of, time, number)) if version_of == name => (time, number),
+                _ => continue,
+            };
+            versions.push((version, entry.path()));
+        }
+        versions.sort_by(|&(version1, _), &(version2, _)| version2.cmp(&version1));
+        for (_, path) in versions.into_iter().skip(self.0.options.archive_keep_versions as usize) {
+            self.remove_archived(&path);
+        }
+    }
+
+    // Deletes every version in the archive directory that was archived before the cutoff.
+    fn prune_archive(&self, dir: &Path, cutoff: SystemTime) {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return,
+            Err(err) => {
+                self.log_error(SyncErrorOp::ListDir, dir, &err);
+                return;
+            },
+        };
+        for entry_result in entries {
+            let entry = match entry_result {
+                Ok(entry) => entry,
+                Err(err) => {
+                    self.log_error(SyncErrorOp::ReadDirEntry, dir, &err);
+                    continue;
+                },
+            };
+            match archive::parse_version_name(&entry.file_name()) {
+                Some((_, time, _)) if time < cutoff => self.remove_archived(&entry.path()),
+                Some(_) => {},
+                None => {
+                    // Directories that aren't versions hold the archived contents of a directory.
+                    if entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+                        self.prune_archive(&entry.path(), cutoff);
+                    }
+                },
             }
-        );
-        for err in read_dir_errors {
-            self.log(SyncLogLevel::Error,
-                     format!("Failed to read the name of a file in {}: {}",
-                     dest_dir.to_string_lossy(), err.description()));
         }
+    }
+
+    fn remove_archived(&self, path: &Path) {
+        let is_dir = fs::symlink_metadata(path).map(|meta| meta.is_dir()).unwrap_or(false);
+        let result = if is_dir { fs::remove_dir_all(path) } else { fs::remove_file(path) };
+        match result {
+            Ok(()) => self.log(SyncEvent::ArchivePruned { path: path.into() }),
+            Err(err) => {
+                let op = if is_dir { SyncErrorOp::DeleteDir } else { SyncErrorOp::DeleteFile };
+                self.log_error(op, path, &err);
+            },
+        }
+    }
+
+    fn plan_operation(&self, op: IoOperation) {
+        let planned = match op {
+            IoOperation::CopyFileIfNeeded(data) => {
+                if data.dest_meta.as_ref().map_or(false, |meta| meta.is_dir()) {
+                    self.0.plan_queue.push(PlannedOperation::DeleteDirAll(data.dest.clone()));
+                }
+                let copy_reason = self.should_copy_file(&data);
+                let len = data.src_meta.len();
+                self.update_progress(|p| {
+                    p.files_compared += 1;
+                    p.bytes_remaining = p.bytes_remaining.saturating_sub(len);
+                    if copy_reason == CopyReason::None {
+                        p.files_skipped += 1;
+                    }
+                });
+                if copy_reason == CopyReason::None {
+                    if self.xattrs_mismatched(&data.src, &data.dest).is_some() {
+                        self.0.plan_queue.push(PlannedOperation::SetXattrs(data.dest.clone()));
+                    }
+                    let mismatched = data.dest_meta.as_ref().map_or(false, |dest_meta| {
+                        self.permissions_mismatched(&data.src_meta, dest_meta)
+                    });
+                    if mismatched {
+                        self.0.plan_queue.push(PlannedOperation::SetPermissions(data.dest));
+                    }
+                    return;
+                }
+                PlannedOperation::CopyFile {
+                    src: data.src,
+                    dest: data.dest,
+                    reason: copy_reason,
+                }
+            },
+            IoOperation::CopySymlink(data) => {
+                if data.dest_meta.as_ref().map_or(false, |meta| meta.is_dir()) {
+                    self.0.plan_queue.push(PlannedOperation::DeleteDirAll(data.dest.clone()));
+                }
+                PlannedOperation::CopySymlink {
+                    src: data.src,
+                    dest: data.dest,
+                    target: data.target,
+                }
+            },
+            IoOperation::HardLink(data) => {
+                let up_to_date = self.hard_link_up_to_date(&data);
+                self.update_progress(|p| {
+                    p.files_compared += 1;
+                    if up_to_date {
+                        p.files_skipped += 1;
+                    }
+                });
+                if up_to_date {
+                    return;
+                }
+                PlannedOperation::HardLink {
+                    src: data.src,
+                    dest: data.dest,
+                    target: data.target,
+                }
+            },
+            IoOperation::DeleteDirAll(dir) => PlannedOperation::DeleteDirAll(dir),
+            IoOperation::DeleteFile(file) => PlannedOperation::DeleteFile(file),
+        };
+        self.0.plan_queue.push(planned);
+    }
+
+    fn update_progress<F: FnOnce(&mut ProgressData)>(&self, f: F) {
+        let mut progress_data = self.0.progress_data.lock().unwrap();
+        f(&mut progress_data);
+    }
+
+    fn log(&self, event: SyncEvent) {
+        let level = event.level();
+        if let SyncLogLevel::Error = level {
+            self.update_progress(|p| p.errors += 1);
+        }
+        self.add_to_report(&event);
+        let entry = SyncLogEntry {
+            time: Instant::now(),
+            level: level,
+            message: event.to_string(),
+            event: event,
+        };
+        let log_sender = self.0.log_sender.lock().unwrap();
+        match *log_sender {
+            // If the receiver was dropped, the entry is thrown away like an unread one in the queue.
+            Some(ref sender) => { let _ = sender.send(entry); },
+            None => self.0.log_queue.push(entry),
+        }
+    }
+
+    fn add_to_report(&self, event: &SyncEvent) {
+        let mut report_data = self.0.report_data.lock().unwrap();
+        let pair_index = match *event {
+            SyncEvent::CopyFinished { ref dest, .. } |
+            SyncEvent::HardLinked { ref dest, .. } |
+            SyncEvent::SymlinkCopied { ref dest, .. } => self.pair_index(dest),
+            SyncEvent::Deleted { ref path, .. } |
+            SyncEvent::Archived { ref path, .. } |
+            SyncEvent::Error { ref path, .. } => self.pair_index(path),
+            SyncEvent::PairRejected { ref src, ref dest, .. } => {
+                self.0.options.directories.iter().position(|&(ref pair_src, ref pair_dest)| {
+                    pair_src == src && pair_dest == dest
+                })
+            },
+            _ => None,
+        };
+        match *event {
+            SyncEvent::Error { ref path, op, kind, ref description } => {
+                report_data.failures.push(SyncFailure {
+                    path: path.clone(),
+                    op,
+                    kind,
+                    description: description.clone(),
+                });
+            },
+            SyncEvent::PairRejected { ref src, ref problem, .. } => {
+                let kind = match *problem {
+                    RootProblem::Missing(_) | RootProblem::MarkerMissing(_) =>
+                        io::ErrorKind::NotFound,
+                    _ => io::ErrorKind::InvalidInput,
+                };
+                report_data.failures.push(SyncFailure {
+                    path: src.clone(),
+                    op: SyncErrorOp::SyncPair,
+                    kind,
+                    description: problem.to_string(),
+                });
+            },
+            SyncEvent::DeletionsRefused { ref guard } => {
+                report_data.deletions_refused = Some(guard.clone());
+                let path = match *guard {
+                    DeletionGuard::EmptySource(ref path) => path.clone(),
+                    _ => PathBuf::new(),
+                };
+                report_data.failures.push(SyncFailure {
+                    path,
+                    op: SyncErrorOp::DeleteExtra,
+                    kind: io::ErrorKind::Other,
+                    description: event.to_string(),
+                });
+            },
+            _ => {},
+        }
+        let pair = match pair_index {
+            Some(pair_index) => &mut report_data.pairs[pair_index],
+            None => return,
+        };
+        match *event {
+            SyncEvent::CopyFinished { size, .. } => {
+                pair.files_copied += 1;
+                pair.bytes_copied += size;
+            },
+            SyncEvent::HardLinked { .. } |
+            SyncEvent::SymlinkCopied { .. } => pair.files_copied += 1,
+            SyncEvent::Deleted { is_dir: true, .. } => pair.dirs_deleted += 1,
+            SyncEvent::Deleted { is_dir: false, .. } => pair.files_deleted += 1,
+            SyncEvent::Archived { .. } => pair.files_archived += 1,
+            SyncEvent::Error { .. } => pair.errors += 1,
+            SyncEvent::PairRejected { ref problem, .. } => {
+                pair.rejected = Some(problem.clone());
+                pair.errors += 1;
+            },
+            _ => {},
+        }
+    }
+
+    // Returns the index of the directory pair that the source or destination path is in.
+    fn pair_index(&self, path: &Path) -> Option<usize> {
+        // Use the innermost directory in case one is inside another.
+        self.0.options.directories.iter().enumerate()
+            .filter_map(|(i, &(ref src, ref dest))| {
+                if path.starts_with(src) {
+                    Some((i, src.components().count()))
+                } else if path.starts_with(dest) {
+                    Some((i, dest.components().count()))
+                } else {
+                    None
+                }
+            })
+            .max_by_key(|&(_, depth)| depth)
+            .map(|(i, _)| i)
+    }
+
+    fn log_error(&self, op: SyncErrorOp, path: &Path, err: &io::Error) {
+        self.log(SyncEvent::Error {
+            path: path.into(),
+            op: op,
+            kind: err.kind(),
+            description: err.description().into(),
+        });
+    }
+
+    fn add_to_sync_dir_queue(&self, src: PathBuf, dest: PathBuf,
+                             dir_filters: Option<Arc<DirFilters>>,
+                             ancestors: Option<Arc<AncestorDirs>>) {
+        self.0.sync_dir_queue.push((src, dest, dir_filters, ancestors));
+        self.0.done_condvar.notify_one();
+    }
+
+    fn add_to_op_queue(&self, op: IoOperation) {
+        if let IoOperation::CopyFileIfNeeded(ref data) = op {
+            let len = data.src_meta.len();
+            self.update_progress(|p| p.bytes_remaining += len);
+        }
+        self.0.op_queue.push(op);
+        self.0.done_condvar.notify_one();
+    }
+
+    // Queues the deletion of something in the destination that isn't in the source, or an operation
+    // that replaces a directory. If there is a deletion guard, it is held back until the guard
+    // checks it.
+    fn add_deletion_to_op_queue(&self, op: IoOperation) {
+        if !self.0.options.has_deletion_guard() {
+            self.add_to_op_queue(op);
+            return;
+        }
+        let entries = deleted_entries(&op);
+        let mut deletion_data = self.0.deletion_data.lock().unwrap();
+        if deletion_data.allowed {
+            drop(deletion_data);
+            self.add_to_op_queue(op);
+            return;
+        }
+        deletion_data.ops.push(op);
+        deletion_data.entries += entries;
+        // The entries inside a deleted directory weren't counted when scanning.
+        deletion_data.dest_entries += entries - 1;
+    }
+
+    // Queues the hard links held back until everything was copied. Returns true if there were any.
+    // It is called with the done lock held, so it must not use `done_data`.
+    fn queue_hard_links(&self) -> bool {
+        let mut hard_links = self.0.hard_links.lock().unwrap();
+        if hard_links.pending.is_empty() {
+            return false;
+        }
+        for op in hard_links.pending.drain(..) {
+            self.0.op_queue.push(op);
+        }
+        self.0.done_condvar.notify_all();
+        true
+    }
+
+    // Holds back syncing a directory whose place in the destination is taken by a file until the
+    // deletion guard allows deleting the file. Returns true if it was held back.
+    fn hold_back_dir(&self, src_dir: &Path, dest_dir: &Path, dir_filters: &Option<Arc<DirFilters>>,
+                     ancestors: &Option<Arc<AncestorDirs>>) -> bool {
+        if !self.0.options.has_deletion_guard() {
+            return false;
+        }
+        let mut deletion_data = self.0.deletion_data.lock().unwrap();
+        if deletion_data.allowed {
+            return false;
+        }
+        deletion_data.dirs.push((src_dir.into(), dest_dir.into(), dir_filters.clone(),
+                                 ancestors.clone()));
+        deletion_data.entries += 1;
+        true
+    }
+
+    // Queues the deletions held back for the deletion guard, or discards them if they exceed a
+    // limit. It is called with the done lock held, so it must not use `done_data`.
+    fn check_deletions(&self) {
+        let mut deletion_data = self.0.deletion_data.lock().unwrap();
+        if deletion_data.ops.is_empty() && deletion_data.dirs.is_empty() {
+            deletion_data.allowed = true;
+            return;
+        }
+        let options = &self.0.options;
+        let mut guard = None;
+        if options.refuse_empty_source {
+            // A pair that was rejected wasn't synced, so it can't have caused any deletions.
+            let synced: Vec<bool> = self.0.report_data.lock().unwrap().pairs.iter()
+                                        .map(|pair| pair.rejected.is_none())
+                                        .collect();
+            let empty_src = options.directories.iter().zip(synced)
+                                   .filter(|&(_, synced)| synced)
+                                   .map(|(&(ref src, _), _)| src)
+                                   .find(|src| {
+                fs::read_dir(src).map(|mut entries| entries.next().is_none()).unwrap_or(true)
+            });
+            guard = empty_src.map(|src| DeletionGuard::EmptySource(src.clone()));
+        }
+        if guard.is_none() && options.max_deleted_entries > 0 &&
+           deletion_data.entries > options.max_deleted_entries {
+            guard = Some(DeletionGuard::MaxEntries {
+                entries: deletion_data.entries,
+                max: options.max_deleted_entries,
+            });
+        }
+        if guard.is_none() && options.max_deleted_percent > 0 &&
+           deletion_data.entries * 100 > deletion_data.dest_entries * options.max_deleted_percent as u64 {
+            guard = Some(DeletionGuard::MaxPercent {
+                entries: deletion_data.entries,
+                dest_entries: deletion_data.dest_entries,
+                max_percent: options.max_deleted_percent,
+            });
+        }
+        match guard {
+            Some(guard) => {
+                deletion_data.ops.clear();
+                deletion_data.dirs.clear();
+                self.log(SyncEvent::DeletionsRefused { guard });
+            },
+            None => {
+                deletion_data.allowed = true;
+                for op in deletion_data.ops.drain(..) {
+                    self.add_to_op_queue(op);
+                }
+                for (src, dest, dir_filters, ancestors) in deletion_data.dirs.drain(..) {
+                    self.add_to_sync_dir_queue(src, dest, dir_filters, ancestors);
+                }
+                self.0.done_condvar.notify_all();
+            },
+        }
+    }
+
+    fn sync_dir(&self, src_dir: &Path, dest_dir: &Path, dir_filters: Option<Arc<DirFilters>>,
+                ancestors: Option<Arc<AncestorDirs>>) {
+        // List the source directory first so that nothing is changed in the destination if it
+        // can't be listed.
+        let src_entries = match fs::read_dir(src_dir) {
+            Ok(src_entries) => src_entries,
+            Err(err) => {
+                self.log_error(SyncErrorOp::ListDir, src_dir, &err);
+                return;
+            },
+        };
+
+        // Kept in case the directory is held back for the deletion guard.
+        let parent_ancestors = ancestors.clone();
+        // When following symlinks, keep the real path of every source directory on the way here so
+        // that a symlink to one of them can be detected.
+        let ancestors = if self.0.options.symlink_policy == SymlinkPolicy::Follow {
+            match src_dir.canonicalize() {
+                Ok(real_dir) => Some(Arc::new(AncestorDirs { parent: ancestors, dir: real_dir })),
+                Err(err) => {
+                    self.log_error(SyncErrorOp::ReadMetadata, src_dir, &err);
+                    return;
+                },
+            }
+        } else {
+            None
+        };
+
+        // If the directory is a file or it doesn't exist, create it.
+        // In a dry run, the destination directory is only listed if it already exists.
+        // The destination directory of a pair may be a symlink to where the files should go, but
+        // a symlink inside it is replaced like a file.
+        let mut dest_exists = true;
+        let is_root = self.0.options.directories.iter().any(|&(_, ref dest)| dest == dest_dir);
+        let dest_meta = if is_root {
+            fs::metadata(&dest_dir)
+        } else {
+            fs::symlink_metadata(&dest_dir)
+        };
+        match dest_meta {
+            Ok(metadata) => {
+                if !metadata.is_dir() {
+                    // The file can't be replaced until the deletion guard allows deleting it.
+                    if self.hold_back_dir(src_dir, dest_dir, &dir_filters, &parent_ancestors) {
+                        return;
+                    }
+                    if self.0.options.dry_run {
+                        self.0.plan_queue.push(PlannedOperation::DeleteFile(dest_dir.into()));
+                        self.0.plan_queue.push(PlannedOperation::CreateDir(dest_dir.into()));
+                        dest_exists = false;
+                    } else {
+                        // The file is archived instead if there is an archive.
+                        self.perform_operation(IoOperation::DeleteFile(dest_dir.into()));
+                        if fs::symlink_metadata(&dest_dir).is_ok() {
+                            return;
+                        }
+                        if let Err(err) = fs::create_dir(&dest_dir) {
+                            self.log_error(SyncErrorOp::CreateDir, dest_dir, &err);
+                            return;
+                        }
+                    }
+                }
+            },
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                if self.0.options.dry_run {
+                    self.0.plan_queue.push(PlannedOperation::CreateDir(dest_dir.into()));
+                    dest_exists = false;
+                } else if let Err(err) = fs::create_dir(&dest_dir) {
+                    self.log_error(SyncErrorOp::CreateDir, dest_dir, &err);
+                    return;
+                }
+            },
+            Err(err) => {
+                self.log_error(SyncErrorOp::ReadMetadata, dest_dir, &err);
+                return;
+            },
+        }
+
+        let options = &self.0.options;
+        if !is_root && (options.copy_permissions || options.copy_ownership || options.copy_xattrs) {
+            match (fs::metadata(src_dir), fs::metadata(dest_dir)) {
+                (Ok(src_meta), Ok(dest_meta)) => {
+                    let permissions = self.permissions_mismatched(&src_meta, &dest_meta);
+                    let xattrs = self.xattrs_mismatched(src_dir, dest_dir);
+                    // A directory that was made read-only by copying its permissions would stop
+                    // this sync from changing its contents, so it's writable until the end.
+                    let mut made_writable = false;
+                    if options.copy_permissions && !options.dry_run {
+                        match permissions::make_writable(dest_dir, &dest_meta) {
+                            Ok(changed) => made_writable = changed,
+                            Err(err) => self.log_error(SyncErrorOp::SetPermissions, dest_dir, &err),
+                        }
+                    }
+                    if options.dry_run {
+                        if xattrs.is_some() {
+                            self.0.plan_queue.push(PlannedOperation::SetXattrs(dest_dir.into()));
+                        }
+                        if permissions {
+                            let planned = PlannedOperation::SetPermissions(dest_dir.into());
+                            self.0.plan_queue.push(planned);
+                        }
+                    } else if permissions || made_writable || xattrs.is_some() {
+                        self.0.dir_attributes.lock().unwrap().push(DirAttributes {
+                            dest: dest_dir.into(),
+                            src_meta,
+                            permissions,
+                            made_writable,
+                            xattrs,
+                        });
+                    }
+                },
+                (Err(err), _) => self.log_error(SyncErrorOp::ReadMetadata, src_dir, &err),
+                // In a dry run, the destination directory isn't created.
+                (_, Err(ref err)) if err.kind() == io::ErrorKind::NotFound => {},
+                (_, Err(err)) => self.log_error(SyncErrorOp::ReadMetadata, dest_dir, &err),
+            }
+        }
+
+        // List the destination directory.
+        let mut dest_entries = if dest_exists {
+            match self.read_dest_dir(dest_dir) {
+                Some(entries) => entries,
+                None => return,
+            }
+        } else {
+            HashMap::new()
+        };
+
+        if self.0.options.has_deletion_guard() {
+            let mut deletion_data = self.0.deletion_data.lock().unwrap();
+            deletion_data.dest_entries += dest_entries.len() as u64;
+        }
+
+        // Add the rules from any filter files in the source directory.
+        let dir_filters = self.read_filter_files(src_dir, dir_filters);
 
         // Copy the contents of the source directory to the destination directory.
-        let src_entries = fs::read_dir(src_dir);
-        let src_entries = src_entries.unwrap(); // TODO: log error instead
         for src_entry_result in src_entries {
             match src_entry_result {
                 Ok(src_entry) => {
                     let src_path = src_entry.path();
-                    // If the filter returns false, skip the file, like it doesn't exist.
-                    if !self.0.options.filter.as_ref().map_or(true, |f| f(&src_path)) {
-                        self.log(SyncLogLevel::Info,
-                                 format!("Skipping file {}", src_path.to_string_lossy()));
-                        continue;
-                    }
-                    let dest_path = dest_dir.join(src_entry.file_name());
-                    let src_meta = match src_entry.metadata() {
+                    let mut src_meta = match src_entry.metadata() {
                         Ok(meta) => meta,
                         Err(err) => {
-                            self.log(SyncLogLevel::Error,
-                                     format!("Failed to read information about {}: {}",
-                                     src_path.to_string_lossy(), err.description()));
+                            self.log_error(SyncErrorOp::ReadMetadata, &src_path, &err);
                             continue;
                         },
                     };
+                    // If a filter excludes the file, skip it, like it doesn't exist.
+                    let options = &self.0.options;
+                    if !options.filter.as_ref().map_or(true, |f| f(&src_path, &src_meta)) ||
+                       !options.metadata_filter.is_included(&src_path, &src_meta) ||
+                       !dir_filters.as_ref().map_or(true, |f| f.is_included(&src_path, src_meta.is_dir()))
+                    {
+                        self.log(SyncEvent::Skipped { path: src_path, reason: SkipReason::Filter });
+                        self.update_progress(|p| p.files_skipped += 1);
+                        continue;
+                    }
+                    let dest_path = dest_dir.join(src_entry.file_name());
+                    if src_meta.file_type().is_symlink() {
+                        match self.0.options.symlink_policy {
+                            SymlinkPolicy::Copy => {
+                                let dest_entry = dest_entries.remove(&dest_path);
+                                self.copy_symlink_if_needed(src_path, dest_path, dest_entry);
+                                continue;
+                            },
+                            SymlinkPolicy::Follow => {
+                                let ancestors = ancestors.as_ref().map(|ancestors| &**ancestors);
+                                match self.follow_symlink(&src_path, ancestors) {
+                                    Ok(Some(meta)) => src_meta = meta,
+                                    Ok(None) | Err(()) => {
+                                        // Keep what's in the destination.
+                                        dest_entries.remove(&dest_path);
+                                        continue;
+                                    },
+                                }
+                            },
+                            SymlinkPolicy::Skip => {
+                                dest_entries.remove(&dest_path);
+                                self.log(SyncEvent::Skipped {
+                                    path: src_path,
+                                    reason: SkipReason::Symlink,
+                                });
+                                self.update_progress(|p| p.files_skipped += 1);
+                                continue;
+                            },
+                        }
+                    }
                     let dest_entry = dest_entries.remove(&dest_path);
                     if src_meta.is_dir() {
-                        self.add_to_sync_dir_queue(src_path, dest_path);
+                        self.add_to_sync_dir_queue(src_path, dest_path, dir_filters.clone(),
+                                                   ancestors.clone());
                     } else if src_meta.is_file() {
                         let dest_meta = dest_entry.map(|entry|
                             entry.metadata()
@@ -343,132 +2060,310 @@ impl SyncOperation {
                                 if err.kind() == io::ErrorKind::NotFound {
                                     None
                                 } else {
-                                    self.log(SyncLogLevel::Error,
-                                             format!("Failed to read information about {}: {}",
-                                             dest_path.to_string_lossy(), err.description()));
+                                    self.log_error(SyncErrorOp::ReadMetadata, &dest_path, &err);
                                     continue;
                                 }
                             }
                             Some(Ok(meta)) => Some(meta),
                             None => None,
                         };
-                        // TODO: this can probably be simplified now or especially once symlinks are
-                        // deleted
-                        let should_copy = match dest_meta {
-                            Some(ref dest_meta) => {
-                                if dest_meta.is_dir() {
-                                    self.add_to_op_queue(IoOperation::DeleteDirAll(dest_path.clone()));
-                                    true
-                                } else if dest_meta.is_file() {
-                                    true
-                                } else {
-                                    self.log(SyncLogLevel::Info,
-                                             format!("Skipping file due to symlink at destination: {}",
-                                             src_path.to_string_lossy()));
-                                    false // TODO: delete symlink?
+                        // A directory in the way is deleted by the copy, so the copy is a deletion
+                        // for the deletion guard. The copy is renamed over a symlink, so the
+                        // symlink is treated like the file isn't in the destination.
+                        let dest_meta = dest_meta.filter(|dest_meta| {
+                            dest_meta.is_dir() || dest_meta.is_file()
+                        });
+                        let replaces_dir = dest_meta.as_ref().map_or(false, |meta| meta.is_dir());
+                        // A file hard linked to one that was already found is linked to its copy,
+                        // unless a directory is in the way.
+                        if self.0.options.preserve_hard_links && !replaces_dir {
+                            if let Some(id) = hard_link_id(&src_meta) {
+                                let mut hard_links = self.0.hard_links.lock().unwrap();
+                                let target = hard_links.targets.entry(id)
+                                                       .or_insert_with(|| dest_path.clone())
+                                                       .clone();
+                                if target != dest_path {
+                                    hard_links.pending.push(IoOperation::HardLink(HardLinkData {
+                                        src: src_path,
+                                        dest: dest_path,
+                                        target,
+                                        src_meta,
+                                    }));
+                                    continue;
                                 }
-                            },
-                            None => true, // The file is not in the destination.
-                        };
-                        if should_copy {
-                            self.add_to_op_queue(IoOperation::CopyFileIfNeeded(CopyFileIfNeededData {
-                                src: src_path,
-                                dest: dest_path,
-                                src_meta,
-                                dest_meta,
-                            }));
+                            }
+                        }
+                        let op = IoOperation::CopyFileIfNeeded(CopyFileIfNeededData {
+                            src: src_path,
+                            dest: dest_path,
+                            src_meta,
+                            dest_meta,
+                        });
+                        if replaces_dir {
+                            self.add_deletion_to_op_queue(op);
+                        } else {
+                            self.add_to_op_queue(op);
                         }
                     }
                 },
                 Err(err) => {
-                    self.log(SyncLogLevel::Error,
-                             format!("Failed to read the name of a file in {}: {}",
-                             src_dir.to_string_lossy(), err.description()));
+                    self.log_error(SyncErrorOp::ReadDirEntry, &src_dir, &err);
                 },
             }
         }
 
-        // Delete anything in the destination directory that isn't in the source.
+        self.update_progress(|p| p.dirs_scanned += 1);
+        self.log(SyncEvent::DirScanned { src: src_dir.into(), dest: dest_dir.into() });
+
+        // Delete anything in the destination directory that isn't in the source. That includes
+        // temporary files left behind by a sync that didn't finish, which can't be the ones this
+        // sync copies into because the process ID is in their names.
         for (dest_path, dest_entry) in dest_entries {
             let dest_meta = match dest_entry.metadata() {
                 Ok(dest_meta) => dest_meta,
                 Err(err) => {
-                    self.log(SyncLogLevel::Error,
-                             format!("Failed to read information about {}: {}",
-                             dest_path.to_string_lossy(), err.description()));
+                    self.log_error(SyncErrorOp::ReadMetadata, &dest_path, &err);
                     continue;
                 },
             };
-            if dest_meta.is_dir() {
-                self.add_to_op_queue(IoOperation::DeleteDirAll(dest_path));
-            } else if dest_meta.is_file() {
-                self.add_to_op_queue(IoOperation::DeleteFile(dest_path));
+            // Protect rules in filter files are matched against the path in the source because
+            // that's where the filter files are.
+            let src_path = src_dir.join(dest_entry.file_name());
+            let dir_filters = dir_filters.as_ref().map(|dir_filters| &**dir_filters);
+            self.delete_unless_protected(dest_path, &dest_meta, &src_path, dir_filters);
+        }
+    }
+
+    // Queues the deletion of something in the destination that isn't in the source unless it is
+    // protected. A directory with something protected inside it is kept, and the rest of what's
+    // inside it is deleted.
+    fn delete_unless_protected(&self, dest_path: PathBuf, dest_meta: &Metadata, src_path: &Path,
+                               dir_filters: Option<&DirFilters>) {
+        if self.is_protected(&dest_path, dest_meta, src_path, dir_filters) {
+            self.log(SyncEvent::Skipped { path: dest_path, reason: SkipReason::Protected });
+            self.update_progress(|p| p.files_skipped += 1);
+            return;
+        }
+        if dest_meta.is_dir() {
+            if self.contains_protected(&dest_path, src_path, dir_filters) {
+                self.delete_unprotected_contents(&dest_path, src_path, dir_filters);
+            } else {
+                self.add_deletion_to_op_queue(IoOperation::DeleteDirAll(dest_path));
             }
+        } else if dest_meta.is_file() || dest_meta.file_type().is_symlink() {
+            self.add_deletion_to_op_queue(IoOperation::DeleteFile(dest_path));
         }
     }
 
-    fn copy_file(&self, src_path: &Path, dest_path: &Path) {
-        let mut src_file = match File::open(src_path) {
-            Ok(file) => file,
-            Err(err) => {
-                self.log(SyncLogLevel::Error,
-                         format!("Failed to open {}: {}",
-                         src_path.to_string_lossy(), err.description()));
-                return;
-            },
+    fn delete_unprotected_contents(&self, dest_dir: &Path, src_dir: &Path,
+                                   dir_filters: Option<&DirFilters>) {
+        let dest_entries = match self.read_dest_dir(dest_dir) {
+            Some(entries) => entries,
+            None => return,
         };
-        let mut dest_file = match File::create(dest_path) {
-            Ok(file) => file,
+        if self.0.options.has_deletion_guard() {
+            let mut deletion_data = self.0.deletion_data.lock().unwrap();
+            deletion_data.dest_entries += dest_entries.len() as u64;
+        }
+        for (dest_path, dest_entry) in dest_entries {
+            match dest_entry.metadata() {
+                Ok(dest_meta) => {
+                    let src_path = src_dir.join(dest_entry.file_name());
+                    self.delete_unless_protected(dest_path, &dest_meta, &src_path, dir_filters);
+                },
+                Err(err) => self.log_error(SyncErrorOp::ReadMetadata, &dest_path, &err),
+            }
+        }
+    }
+
+    fn is_protected(&self, dest_path: &Path, dest_meta: &Metadata, src_path: &Path,
+                    dir_filters: Option<&DirFilters>) -> bool {
+        self.0.options.protect.as_ref().map_or(false, |f| f(dest_path, dest_meta)) ||
+        dir_filters.map_or(false, |f| f.is_protected(src_path, dest_meta.is_dir()))
+    }
+
+    // Returns true if anything inside the directory in the destination is protected.
+    fn contains_protected(&self, dest_dir: &Path, src_dir: &Path,
+                          dir_filters: Option<&DirFilters>) -> bool {
+        if self.0.options.protect.is_none() &&
+           !dir_filters.map_or(false, |f| f.has_protect_rules()) {
+            return false;
+        }
+        let entries = match fs::read_dir(dest_dir) {
+            Ok(entries) => entries,
+            Err(_) => return false,
+        };
+        entries.filter_map(|entry| entry.ok()).any(|entry| {
+            let dest_meta = match entry.metadata() {
+                Ok(dest_meta) => dest_meta,
+                Err(_) => return false,
+            };
+            let dest_path = entry.path();
+            let src_path = src_dir.join(entry.file_name());
+            self.is_protected(&dest_path, &dest_meta, &src_path, dir_filters) ||
+            (dest_meta.is_dir() && self.contains_protected(&dest_path, &src_path, dir_filters))
+        })
+    }
+
+    // Returns the metadata of what a symlink in the source points to, or `None` if the symlink is
+    // skipped because it points to nothing or to a directory containing it.
+    fn follow_symlink(&self, path: &Path, ancestors: Option<&AncestorDirs>)
+                      -> Result<Option<Metadata>, ()> {
+        let meta = match fs::metadata(path) {
+            Ok(meta) => meta,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                self.log(SyncEvent::Skipped { path: path.into(), reason: SkipReason::Symlink });
+                self.update_progress(|p| p.files_skipped += 1);
+                return Ok(None);
+            },
             Err(err) => {
-                self.log(SyncLogLevel::Error,
-                         format!("Failed to open {}: {}",
-                         dest_path.to_string_lossy(), err.description()));
-                return;
+                self.log_error(SyncErrorOp::ReadMetadata, path, &err);
+                return Err(());
             },
         };
-        self.log(SyncLogLevel::Info, format!("Starting to copy {}", src_path.to_string_lossy()));
-        if let Err(err) = io::copy(&mut src_file, &mut dest_file) {
-            self.log(SyncLogLevel::Error,
-                     format!("Failed to copy {}: {}",
-                     src_path.to_string_lossy(), err.description()));
+        if meta.is_dir() {
+            let real_path = match path.canonicalize() {
+                Ok(real_path) => real_path,
+                Err(err) => {
+                    self.log_error(SyncErrorOp::ReadLink, path, &err);
+                    return Err(());
+                },
+            };
+            if ancestors.map_or(false, |ancestors| ancestors.any_inside(&real_path)) {
+                self.log(SyncEvent::Skipped { path: path.into(), reason: SkipReason::SymlinkLoop });
+                self.update_progress(|p| p.files_skipped += 1);
+                return Ok(None);
+            }
         }
+        Ok(Some(meta))
     }
 
-    fn compare_start_end_equal(&self, data: &CopyFileIfNeededData) -> Result<bool, ()> {
-        let mut src_file = match File::open(&data.src) {
-            Ok(file) => file,
+    // Queues copying a symlink in the source unless the destination has one with the same target.
+    fn copy_symlink_if_needed(&self, src: PathBuf, dest: PathBuf, dest_entry: Option<DirEntry>) {
+        let target = match fs::read_link(&src) {
+            Ok(target) => target,
             Err(err) => {
-                self.log(SyncLogLevel::Error,
-                         format!("Failed to open {}: {}",
-                         data.src.to_string_lossy(), err.description()));
-                return Err(());
+                self.log_error(SyncErrorOp::ReadLink, &src, &err);
+                return;
             },
         };
-        let mut dest_file = match File::open(&data.dest) {
-            Ok(file) => file,
-            Err(_) => {
-                return Err(());
+        let dest_meta = match dest_entry.map(|entry| entry.metadata()) {
+            Some(Ok(meta)) => Some(meta),
+            Some(Err(ref err)) if err.kind() == io::ErrorKind::NotFound => None,
+            Some(Err(err)) => {
+                self.log_error(SyncErrorOp::ReadMetadata, &dest, &err);
+                return;
             },
+            None => None,
         };
-
-        let mut compare_size = self.0.options.copy_contents_if_start_end_mismatched_size as u64;
-        compare_size = cmp::min(compare_size, data.src_meta.len());
-        if let Some(ref dest_meta) = data.dest_meta {
-            compare_size = cmp::min(compare_size, dest_meta.len());
+        let up_to_date = dest_meta.as_ref().map_or(false, |meta| meta.file_type().is_symlink()) &&
+                         fs::read_link(&dest).ok().as_ref() == Some(&target);
+        self.update_progress(|p| {
+            p.files_compared += 1;
+            if up_to_date {
+                p.files_skipped += 1;
+            }
+        });
+        if !up_to_date {
+            let replaces_dir = dest_meta.as_ref().map_or(false, |meta| meta.is_dir());
+            let op = IoOperation::CopySymlink(CopySymlinkData {
+                src,
+                dest,
+                target,
+                dest_meta,
+            });
+            if replaces_dir {
+                self.add_deletion_to_op_queue(op);
+            } else {
+                self.add_to_op_queue(op);
+            }
         }
-        let compare_size = compare_size as usize;
+    }
 
-        let mut src_buffer = Vec::new();
-        src_buffer.resize(compare_size, 0);
-        let mut dest_buffer = Vec::new();
+    fn read_filter_files(&self, src_dir: &Path, parent: Option<Arc<DirFilters>>)
+                         -> Option<Arc<DirFilters>> {
+        let mut rules = FilterRules::new();
+        for name in &self.0.options.filter_file_names {
+            let path = src_dir.join(name);
+            let mut text = String::new();
+            match File::open(&path).and_then(|mut file| file.read_to_string(&mut text)) {
+                Ok(_) => {
+                    let file_rules = if name == GITIGNORE_FILE_NAME {
+                        FilterRules::from_gitignore(&text)
+                    } else {
+                        FilterRules::from_filter_file(&text)
+                    };
+                    for rule in file_rules.rules() {
+                        rules.push(rule.clone());
+                    }
+                },
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => {},
+                Err(err) => self.log_error(SyncErrorOp::Read, &path, &err),
+            }
+        }
+        if rules.is_empty() {
+            parent
+        } else {
+            Some(Arc::new(DirFilters {
+                parent: parent,
+                dir: src_dir.into(),
+                rules: rules,
+            }))
+        }
+    }
+
+    fn read_dest_dir(&self, dest_dir: &Path) -> Option<HashMap<PathBuf, DirEntry>> {
+        let dest_entries = match fs::read_dir(dest_dir) {
+            Ok(entries) => entries,
+            Err(err) => {
+                self.log_error(SyncErrorOp::ListDir, &dest_dir, &err);
+                return None;
+            },
+        };
+        let (dest_entries, read_dir_errors): (HashMap<_, _>, Vec<_>) = dest_entries
+                                                                       .partition_map(|res|
+            match res {
+                Ok(entry) => Partition::Left((entry.path(), entry)),
+                Err(err) => Partition::Right(err),
+            }
+        );
+        for err in read_dir_errors {
+            self.log_error(SyncErrorOp::ReadDirEntry, &dest_dir, &err);
+        }
+        Some(dest_entries)
+    }
+
+    fn compare_start_end_equal(&self, data: &CopyFileIfNeededData) -> Result<bool, ()> {
+        let mut src_file = match File::open(&data.src) {
+            Ok(file) => file,
+            Err(err) => {
+                self.log_error(SyncErrorOp::Open, &data.src, &err);
+                return Err(());
+            },
+        };
+        let mut dest_file = match File::open(&data.dest) {
+            Ok(file) => file,
+            Err(_) => {
+                return Err(());
+            },
+        };
+
+        let mut compare_size = self.0.options.copy_contents_if_start_end_mismatched_size as u64;
+        compare_size = cmp::min(compare_size, data.src_meta.len());
+        if let Some(ref dest_meta) = data.dest_meta {
+            compare_size = cmp::min(compare_size, dest_meta.len());
+        }
+        let compare_size = compare_size as usize;
+
+        let mut src_buffer = Vec::new();
+        src_buffer.resize(compare_size, 0);
+        let mut dest_buffer = Vec::new();
         dest_buffer.resize(compare_size, 0);
 
         match src_file.read_exact(&mut src_buffer) {
             Ok(size) => size,
             Err(err) => {
-                self.log(SyncLogLevel::Error,
-                         format!("Failed to read {}: {}",
-                         data.src.to_string_lossy(), err.description()));
+                self.log_error(SyncErrorOp::Read, &data.src, &err);
                          return Err(());
             }
         };
@@ -484,9 +2379,7 @@ impl SyncOperation {
         }
 
         if let Err(err) = src_file.seek(SeekFrom::End(-(compare_size as i64))) {
-            self.log(SyncLogLevel::Error,
-                     format!("Failed to seek {}: {}",
-                     data.src.to_string_lossy(), err.description()));
+            self.log_error(SyncErrorOp::Seek, &data.src, &err);
             return Err(());
         }
         if let Err(_) = dest_file.seek(SeekFrom::End(-(compare_size as i64))) {
@@ -496,9 +2389,7 @@ impl SyncOperation {
         match src_file.read_exact(&mut src_buffer) {
             Ok(size) => size,
             Err(err) => {
-                self.log(SyncLogLevel::Error,
-                         format!("Failed to seek {}: {}",
-                         data.src.to_string_lossy(), err.description()));
+                self.log_error(SyncErrorOp::Read, &data.src, &err);
                 return Err(());
             }
         };
@@ -516,27 +2407,70 @@ impl SyncOperation {
         Ok(true)
     }
 
+    fn compare_contents_equal(&self, data: &CopyFileIfNeededData) -> Result<bool, ()> {
+        let mut src_file = match File::open(&data.src) {
+            Ok(file) => file,
+            Err(err) => {
+                self.log_error(SyncErrorOp::Open, &data.src, &err);
+                return Err(());
+            },
+        };
+        let mut dest_file = match File::open(&data.dest) {
+            Ok(file) => file,
+            Err(_) => {
+                return Err(());
+            },
+        };
+
+        let mut src_buffer = Vec::new();
+        src_buffer.resize(COMPARE_CHUNK_SIZE, 0);
+        let mut dest_buffer = Vec::new();
+        dest_buffer.resize(COMPARE_CHUNK_SIZE, 0);
+
+        loop {
+            let src_size = match read_chunk(&mut src_file, &mut src_buffer) {
+                Ok(size) => size,
+                Err(err) => {
+                    self.log_error(SyncErrorOp::Read, &data.src, &err);
+                    return Err(());
+                }
+            };
+            let dest_size = match read_chunk(&mut dest_file, &mut dest_buffer) {
+                Ok(size) => size,
+                Err(_) => {
+                    return Err(());
+                }
+            };
+
+            if src_buffer[..src_size] != dest_buffer[..dest_size] {
+                return Ok(false);
+            }
+            // Both files hit the end at the same point.
+            if src_size < COMPARE_CHUNK_SIZE {
+                return Ok(true);
+            }
+        }
+    }
+
     fn should_copy_file(&self, data: &CopyFileIfNeededData) -> CopyReason {
         // Compare the modified date and size, depending on settings.
         let src_modified = match data.src_meta.modified() {
             Ok(modified) => modified,
             Err(err) => {
-                self.log(SyncLogLevel::Error,
-                         format!("Failed to get modified date of {}: {}",
-                         data.src.to_string_lossy(), err.description()));
+                self.log_error(SyncErrorOp::GetModified, &data.src, &err);
                 return CopyReason::DateMismatched;
             },
         };
         let dest_meta = match data.dest_meta {
+            // A directory in the way is replaced no matter what.
+            Some(ref meta) if meta.is_dir() => return CopyReason::Missing,
             Some(ref meta) => meta,
             None => return CopyReason::Missing,
         };
         let dest_modified = match dest_meta.modified() {
             Ok(modified) => modified,
             Err(err) => {
-                self.log(SyncLogLevel::Error,
-                         format!("Failed to get modified date of {}: {}",
-                         data.dest.to_string_lossy(), err.description()));
+                self.log_error(SyncErrorOp::GetModified, &data.dest, &err);
                 return CopyReason::DateMismatched;
             },
         };
@@ -552,6 +2486,10 @@ impl SyncOperation {
             !self.compare_start_end_equal(&data).unwrap_or(false)
         {
             CopyReason::StartEndMismatched
+        } else if self.0.options.copy_contents_if_contents_mismatched &&
+            !self.compare_contents_equal(&data).unwrap_or(false)
+        {
+            CopyReason::ContentsMismatched
         } else {
             CopyReason::None
         }
@@ -559,141 +2497,2018 @@ impl SyncOperation {
 
     fn copy_file_if_needed(&self, data: CopyFileIfNeededData) {
         let copy_reason = self.should_copy_file(&data);
+        let len = data.src_meta.len();
+        self.update_progress(|p| {
+            p.files_compared += 1;
+            p.bytes_remaining = p.bytes_remaining.saturating_sub(len);
+            if copy_reason == CopyReason::None {
+                p.files_skipped += 1;
+            }
+        });
         if copy_reason == CopyReason::None {
+            // The contents are up to date, but the attributes and permissions might not be.
+            if let Some(attrs) = self.xattrs_mismatched(&data.src, &data.dest) {
+                if self.write_xattrs(&data.dest, &attrs) {
+                    self.log(SyncEvent::XattrsCopied { path: data.dest.clone() });
+                }
+            }
+            if let Some(ref dest_meta) = data.dest_meta {
+                if self.permissions_mismatched(&data.src_meta, dest_meta) &&
+                   self.copy_permissions(&data.src_meta, &data.dest)
+                {
+                    self.log(SyncEvent::PermissionsCopied { path: data.dest.clone() });
+                }
+            }
             return;
         }
 
         let mut src_file = match File::open(&data.src) {
             Ok(file) => file,
             Err(err) => {
-                self.log(SyncLogLevel::Error,
-                         format!("Failed to open {}: {}",
-                         data.src.to_string_lossy(), err.description()));
+                self.log_error(SyncErrorOp::Open, &data.src, &err);
                 return;
             },
         };
-        let mut dest_file = match File::create(&data.dest) {
+        // Copy into a temporary file next to the destination and then rename it over the
+        // destination, so that the existing copy is never lost if the copy fails partway through.
+        let temp_path = temp_file_path(&data.dest);
+        let mut temp_file = match File::create(&temp_path) {
             Ok(file) => file,
             Err(err) => {
-                self.log(SyncLogLevel::Error,
-                         format!("Failed to open {}: {}",
-                         data.dest.to_string_lossy(), err.description()));
+                self.log_error(SyncErrorOp::Open, &temp_path, &err);
+                return;
+            },
+        };
+
+        self.log(SyncEvent::CopyStarted {
+            src: data.src.clone(),
+            dest: data.dest.clone(),
+            reason: copy_reason,
+            size: len,
+        });
+        let copied_size = match self.copy_contents(&mut src_file, &mut temp_file) {
+            Ok(Some(size)) => size,
+            Ok(None) => {
+                drop(temp_file);
+                if self.remove_temp_file(&temp_path) {
+                    self.log(SyncEvent::CopyCancelled {
+                        src: data.src.clone(),
+                        dest: data.dest.clone(),
+                    });
+                }
+                return;
+            },
+            Err(err) => {
+                self.log_error(SyncErrorOp::Copy, &data.src, &err);
+                drop(temp_file);
+                self.remove_temp_file(&temp_path);
                 return;
             },
         };
+        if let Err(err) = temp_file.sync_all() {
+            self.log_error(SyncErrorOp::Flush, &temp_path, &err);
+            drop(temp_file);
+            self.remove_temp_file(&temp_path);
+            return;
+        }
+        // Close the file first so that nothing written afterward changes the modified date.
+        drop(temp_file);
+
+        self.copy_file_times(&data, &temp_path);
+        // ACLs are copied before the permissions because setting them changes the permissions.
+        if self.xattrs_enabled(&temp_path) {
+            if let Some(attrs) = self.read_xattrs(&data.src) {
+                self.write_xattrs(&temp_path, &attrs);
+            }
+        }
+        self.copy_permissions(&data.src_meta, &temp_path);
 
-        self.log(SyncLogLevel::Info,
-            format!("{:?}: Starting to copy {}", copy_reason, data.src.to_string_lossy()));
-        if let Err(err) = io::copy(&mut src_file, &mut dest_file) {
-            self.log(SyncLogLevel::Error,
-                     format!("Failed to copy {}: {}",
-                     data.src.to_string_lossy(), err.description()));
+        // Keep the old file in the archive, and leave it alone if it can't be archived.
+        let replacing = data.dest_meta.as_ref().map_or(false, |meta| meta.is_file());
+        if replacing && self.0.options.archive_dir.is_some() && !self.archive(&data.dest, false) {
+            self.remove_temp_file(&temp_path);
+            return;
+        }
+        // A file can't be renamed over a directory, so the directory is deleted first.
+        if data.dest_meta.as_ref().map_or(false, |meta| meta.is_dir()) {
+            self.perform_operation(IoOperation::DeleteDirAll(data.dest.clone()));
+            if fs::symlink_metadata(&data.dest).is_ok() {
+                self.remove_temp_file(&temp_path);
+                return;
+            }
         }
+
+        if let Err(err) = fs::rename(&temp_path, &data.dest) {
+            self.log_error(SyncErrorOp::Rename, &data.dest, &err);
+            self.remove_temp_file(&temp_path);
+            return;
+        }
+        self.update_progress(|p| p.files_copied += 1);
+        self.log(SyncEvent::CopyFinished {
+            src: data.src.clone(),
+            dest: data.dest.clone(),
+            size: copied_size,
+        });
     }
 
-}
+    // Returns true if the destination is already the same file as the target.
+    fn hard_link_up_to_date(&self, data: &HardLinkData) -> bool {
+        match (fs::metadata(&data.target), fs::symlink_metadata(&data.dest)) {
+            (Ok(target_meta), Ok(dest_meta)) => is_same_file(&target_meta, &dest_meta),
+            _ => false,
+        }
+    }
 
-struct CopyFileIfNeededData {
-        pub src: PathBuf,
-        pub dest: PathBuf,
-        pub src_meta: Metadata,
-        pub dest_meta: Option<Metadata>,
+    fn hard_link_file(&self, data: HardLinkData) {
+        if self.hard_link_up_to_date(&data) {
+            self.update_progress(|p| {
+                p.files_compared += 1;
+                p.files_skipped += 1;
+            });
+            return;
+        }
+        let dest_meta = match fs::symlink_metadata(&data.dest) {
+            Ok(meta) => Some(meta),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => {
+                self.log_error(SyncErrorOp::ReadMetadata, &data.dest, &err);
+                return;
+            },
+        };
+        // Like a copied file, the link is made next to the destination and renamed over it.
+        let temp_path = temp_file_path(&data.dest);
+        if fs::hard_link(&data.target, &temp_path).is_err() {
+            // The target's copy might have failed or be on a different drive, so copy the file.
+            // The link wasn't counted as bytes to copy when it was found, so it is counted now.
+            let len = data.src_meta.len();
+            self.update_progress(|p| p.bytes_remaining += len);
+            self.copy_file_if_needed(CopyFileIfNeededData {
+                src: data.src,
+                dest: data.dest,
+                src_meta: data.src_meta,
+                dest_meta: dest_meta.filter(|meta| !meta.file_type().is_symlink()),
+            });
+            return;
+        }
+        self.update_progress(|p| p.files_compared += 1);
+
+        // Keep the old file in the archive, and leave it alone if it can't be archived.
+        let replacing = dest_meta.as_ref().map_or(false, |meta| meta.is_file());
+        if replacing && self.0.options.archive_dir.is_some() && !self.archive(&data.dest, false) {
+            self.remove_temp_file(&temp_path);
+            return;
+        }
+        if let Err(err) = fs::rename(&temp_path, &data.dest) {
+            self.log_error(SyncErrorOp::Rename, &data.dest, &err);
+            self.remove_temp_file(&temp_path);
+            return;
+        }
+        self.update_progress(|p| p.files_copied += 1);
+        self.log(SyncEvent::HardLinked {
+            src: data.src,
+            dest: data.dest,
+            target: data.target,
+        });
     }
 
-enum IoOperation {
-    DeleteDirAll(PathBuf),
-    DeleteFile(PathBuf),
-    CopyFileIfNeeded(CopyFileIfNeededData),
-}
+    fn copy_symlink(&self, data: CopySymlinkData) {
+        // A symlink can't be renamed over a directory, so the directory is deleted first. A file is
+        // archived before the symlink replaces it.
+        match data.dest_meta {
+            Some(ref dest_meta) if dest_meta.is_dir() => {
+                self.perform_operation(IoOperation::DeleteDirAll(data.dest.clone()));
+                if fs::symlink_metadata(&data.dest).is_ok() {
+                    return;
+                }
+            },
+            Some(ref dest_meta) if dest_meta.is_file() && self.0.options.archive_dir.is_some() => {
+                if !self.archive(&data.dest, false) {
+                    return;
+                }
+            },
+            _ => {},
+        }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum CopyReason {
-    Missing,
-    DateMismatched,
-    SizeMismatched,
-    StartEndMismatched,
-    None,
-}
+        // Like a copied file, the symlink is created next to the destination and renamed over it.
+        let temp_path = temp_file_path(&data.dest);
+        let is_dir = fs::metadata(&data.src).map(|meta| meta.is_dir()).unwrap_or(false);
+        if let Err(err) = create_symlink(&data.target, &temp_path, is_dir) {
+            self.log_error(SyncErrorOp::CreateSymlink, &temp_path, &err);
+            return;
+        }
+        if let Err(err) = fs::rename(&temp_path, &data.dest) {
+            self.log_error(SyncErrorOp::Rename, &data.dest, &err);
+            self.remove_temp_file(&temp_path);
+            return;
+        }
+        self.update_progress(|p| p.files_copied += 1);
+        self.log(SyncEvent::SymlinkCopied {
+            src: data.src,
+            dest: data.dest,
+            target: data.target,
+        });
+    }
 
+    // Returns true if the file was deleted.
+    fn remove_temp_file(&self, temp_path: &Path) -> bool {
+        if let Err(err) = remove_file_or_link(temp_path) {
+            self.log_error(SyncErrorOp::DeletePartial, temp_path, &err);
+            false
+        } else {
+            true
+        }
+    }
 
-#[cfg(test)]
-mod tests {
-    use std::env;
-    use std::fs::{self, File};
-    use std::io::{self, Read, Write};
-    use std::path::Path;
-    use std::thread;
-    use std::time::Duration;
-    use super::SyncBuilder;
+    // Copies the file in chunks so that pausing or cancelling doesn't have to wait for a large file
+    // to finish. Returns the number of bytes copied or `None` if the operation was cancelled before
+    // the copy finished.
+    //
+    // Only the regions of the file with data are copied. The holes of a sparse file are skipped,
+    // and seeking over them in the new destination file leaves holes there too.
+    fn copy_contents(&self, src_file: &mut File, dest_file: &mut File) -> io::Result<Option<u64>> {
+        let len = src_file.metadata()?.len();
+        let mut buffer = Vec::new();
+        buffer.resize(COPY_CHUNK_SIZE, 0);
+        let mut total = 0;
+        for (start, end) in sparse::data_regions(src_file, len)? {
+            src_file.seek(SeekFrom::Start(start))?;
+            dest_file.seek(SeekFrom::Start(start))?;
+            let mut pos = start;
+            while pos < end {
+                if !self.wait_if_paused() {
+                    return Ok(None);
+                }
+                let chunk_len = cmp::min(buffer.len() as u64, end - pos) as usize;
+                let size = match src_file.read(&mut buffer[..chunk_len]) {
+                    // The file was made shorter while it was being copied, so what was copied
+                    // isn't a copy of any version of it.
+                    Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                                       "the file got shorter while it was copied")),
+                    Ok(size) => size,
+                    Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(err) => return Err(err),
+                };
+                dest_file.write_all(&buffer[..size])?;
+                pos += size as u64;
+                total += size as u64;
+                self.update_progress(|p| p.bytes_copied += size as u64);
+            }
+        }
+        // A hole at the end isn't written, so the length has to be set.
+        dest_file.set_len(len)?;
+        self.update_progress(|p| p.sparse_bytes_skipped += len.saturating_sub(total));
+        Ok(Some(total))
+    }
 
-    fn read_file<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, io::Error> {
-        let mut f = File::open(path)?;
-        let metadata = f.metadata()?;
-        let mut data = Vec::with_capacity(metadata.len() as usize);
-        f.read_to_end(&mut data)?;
-        Ok(data)
+    // Returns true if the permissions or owner of the destination should be changed to match the
+    // source, depending on settings.
+    fn permissions_mismatched(&self, src_meta: &Metadata, dest_meta: &Metadata) -> bool {
+        let options = &self.0.options;
+        (options.copy_permissions && !permissions::mode_equal(src_meta, dest_meta)) ||
+        (options.copy_ownership && permissions::can_set_owner() &&
+         !permissions::owner_equal(src_meta, dest_meta))
     }
 
-    fn write_file<P: AsRef<Path>>(path: P, data: &[u8]) -> io::Result<()> {
-        let mut file = fs::File::create(path)?;
-        file.write_all(data)?;
-        Ok(())
+    // Sets the permissions and owner of `path` to those of the source, depending on settings.
+    // Returns true if it succeeded.
+    fn copy_permissions(&self, src_meta: &Metadata, path: &Path) -> bool {
+        // The owner is set first because changing it clears the setuid and setgid bits.
+        if self.0.options.copy_ownership && permissions::can_set_owner() {
+            if let Err(err) = permissions::set_owner(path, src_meta) {
+                self.log_error(SyncErrorOp::SetOwner, path, &err);
+                return false;
+            }
+        }
+        if self.0.options.copy_permissions {
+            if let Err(err) = permissions::set_mode(path, src_meta) {
+                self.log_error(SyncErrorOp::SetPermissions, path, &err);
+                return false;
+            }
+        }
+        true
     }
 
-    fn list_dir<P: AsRef<Path>>(path: P) -> Result<Vec<String>, io::Error> {
-        let entries = fs::read_dir(path)?;
-        let mut entry_info = vec![];
-        for entry in entries {
-            let entry = entry?;
-            let meta = entry.metadata()?;
-            let data = if meta.is_file() {
-                Some(read_file(entry.path())?)
-            } else {
+    // Returns true if extended attributes should be copied to the path in the destination.
+    fn xattrs_enabled(&self, dest: &Path) -> bool {
+        self.0.options.copy_xattrs && self.pair_index(dest).map_or(true, |pair_index| {
+            !self.0.xattrs_unsupported.lock().unwrap()[pair_index]
+        })
+    }
+
+    // Returns the extended attributes of the source if they should be copied because they are
+    // different from the destination's.
+    fn xattrs_mismatched(&self, src: &Path, dest: &Path) -> Option<Vec<(OsString, Vec<u8>)>> {
+        if !self.xattrs_enabled(dest) {
+            return None;
+        }
+        let src_attrs = self.read_xattrs(src)?;
+        match xattrs::read(dest) {
+            Ok(ref dest_attrs) if *dest_attrs == src_attrs => None,
+            Ok(_) => Some(src_attrs),
+            Err(err) => {
+                self.log_error(SyncErrorOp::ReadXattrs, dest, &err);
                 None
-            };
-            entry_info.push((meta, entry.file_name(), data));
+            },
         }
-        entry_info.sort_by(|&(_, ref file_name1, _), &(_, ref file_name2, _)| file_name1.cmp(file_name2));
-        Ok(entry_info.iter().map(|&(ref metadata, ref file_name, ref data)| {
-            format!("{}:{}:{}", if metadata.is_dir() { "D" } else { "F" }, file_name.to_string_lossy(),
-                    data.as_ref().map(|d| String::from_utf8_lossy(d)).unwrap_or("".into()))
-        }).collect())
     }
 
-    #[test]
-    fn test_basic_sync() {
-        let temp_dir = env::temp_dir();
-        let src_dir = temp_dir.join("SyncBuilderTestsSource");
-        let _ = fs::remove_dir_all(&src_dir);
-        fs::create_dir(&src_dir).expect("failed to create SyncBuilderTestsSource");
-        let dest_dir = temp_dir.join("SyncBuilderTestsDest");
-        let _ = fs::remove_dir_all(&dest_dir);
-        fs::create_dir(&dest_dir).expect("failed to create SyncBuilderTestsDest");
+    fn read_xattrs(&self, src: &Path) -> Option<Vec<(OsString, Vec<u8>)>> {
+        match xattrs::read(src) {
+            Ok(attrs) => Some(attrs),
+            Err(err) => {
+                self.log_error(SyncErrorOp::ReadXattrs, src, &err);
+                None
+            },
+        }
+    }
 
-        write_file(src_dir.join("banana.txt"), b"cd").expect("failed to create banana.txt");
-        write_file(src_dir.join("cherry.txt"), b"de").expect("failed to create cherry.txt");
-        write_file(src_dir.join("grape.txt"), b"hi").expect("failed to create grape.txt");
-        fs::create_dir(src_dir.join("peach.txt")).expect("failed to create peach.txt");
+    // Sets the extended attributes of `path`. Returns true if it succeeded. If the file system
+    // doesn't support them, that is logged once for the directory pair instead of as an error.
+    fn write_xattrs(&self, path: &Path, attrs: &[(OsString, Vec<u8>)]) -> bool {
+        match xattrs::write(path, attrs) {
+            Ok(()) => true,
+            Err(ref err) if xattrs::is_unsupported(err) => {
+                if let Some(pair_index) = self.pair_index(path) {
+                    let reported = {
+                        let mut xattrs_unsupported = self.0.xattrs_unsupported.lock().unwrap();
+                        mem::replace(&mut xattrs_unsupported[pair_index], true)
+                    };
+                    if !reported {
+                        let dest = self.0.options.directories[pair_index].1.clone();
+                        self.log(SyncEvent::XattrsUnsupported { dest });
+                    }
+                }
+                false
+            },
+            Err(err) => {
+                self.log_error(SyncErrorOp::SetXattrs, path, &err);
+                false
+            },
+        }
+    }
 
-        write_file(dest_dir.join("apple.txt"), b"bc").expect("failed to create apple.txt");
-        fs::create_dir(dest_dir.join("cherry.txt")).expect("failed to create cherry.txt");
-        write_file(dest_dir.join("grape.txt"), b"hij").expect("failed to create grape.txt");
-        write_file(dest_dir.join("peach.txt"), b"qr").expect("failed to create peach.txt");
+    // Sets the attributes of the directories that were held back until the end. The deepest ones
+    // are done first so that a directory that can't be entered doesn't get in the way.
+    fn copy_dir_attributes(&self) {
+        let mut dirs = mem::replace(&mut *self.0.dir_attributes.lock().unwrap(), vec![]);
+        dirs.sort_by(|dir1, dir2| {
+            dir2.dest.components().count().cmp(&dir1.dest.components().count())
+        });
+        for dir in dirs {
+            if let Some(ref attrs) = dir.xattrs {
+                if self.write_xattrs(&dir.dest, attrs) {
+                    self.log(SyncEvent::XattrsCopied { path: dir.dest.clone() });
+                }
+            }
+            if dir.permissions {
+                if self.copy_permissions(&dir.src_meta, &dir.dest) {
+                    self.log(SyncEvent::PermissionsCopied { path: dir.dest });
+                }
+            } else if dir.made_writable {
+                // The mode matched the source before it was made writable.
+                if let Err(err) = permissions::set_mode(&dir.dest, &dir.src_meta) {
+                    self.log_error(SyncErrorOp::SetPermissions, &dir.dest, &err);
+                }
+            }
+        }
+    }
 
-        let op = SyncBuilder::new().add_directory_pair(src_dir.clone(), dest_dir.clone()).sync();
-        while !op.is_done() {
-            thread::sleep(Duration::from_millis(100));
+    // Sets the dates of `path` to the dates of the source file.
+    fn copy_file_times(&self, data: &CopyFileIfNeededData, path: &Path) {
+        // The source's created date isn't even read if it can't be set, because many file systems
+        // don't have one and reading it would fail.
+        if self.0.options.copy_created_date && file_times::can_set_created() {
+            match data.src_meta.created() {
+                Ok(created) => {
+                    if let Err(err) = file_times::set_created(path, created) {
+                        self.log_error(SyncErrorOp::SetCreated, path, &err);
+                    }
+                },
+                Err(err) => {
+                    self.log_error(SyncErrorOp::GetCreated, &data.src, &err);
+                },
+            }
+        }
+        if self.0.options.copy_modified_date {
+            match data.src_meta.modified() {
+                Ok(modified) => {
+                    if let Err(err) = file_times::set_modified(path, modified) {
+                        self.log_error(SyncErrorOp::SetModified, path, &err);
+                    }
+                },
+                Err(err) => {
+                    self.log_error(SyncErrorOp::GetModified, &data.src, &err);
+                },
+            }
         }
+    }
 
-        let dest_list = list_dir(&dest_dir).expect("failed to list dir");
-        assert_eq!(dest_list, &[
-            "F:banana.txt:cd",
-            "F:cherry.txt:de",
-            "F:grape.txt:hi",
-            "D:peach.txt:",
+}
+
+const DEFAULT_FILTER_FILE_NAME: &'static str = ".mirrorignore";
+
+// A filter file with this name is parsed with `FilterRules::from_gitignore()`.
+const GITIGNORE_FILE_NAME: &'static str = ".gitignore";
+
+// The number of bytes read from each file at a time when comparing the entire contents.
+const COMPARE_CHUNK_SIZE: usize = 256 * 1024;
+
+// The number of bytes copied at a time. The operation can only be paused or cancelled between
+// chunks.
+const COPY_CHUNK_SIZE: usize = 1024 * 1024;
+
+// Appended to the name of the temporary file that a file is copied into before it replaces the
+// destination.
+const TEMP_FILE_SUFFIX: &'static str = ".mirror-sync-tmp";
+
+fn temp_file_path(dest: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(dest.file_name().unwrap_or(OsStr::new("")));
+    name.push(format!(".{}", process::id()));
+    name.push(TEMP_FILE_SUFFIX);
+    dest.with_file_name(name)
+}
+
+// Estimates how long copying the remaining bytes will take at the rate they have been copied so
+// far. There is no estimate before anything is copied, or if the rate was measured over so little
+// time that the estimate doesn't fit in a `Duration`.
+fn estimate_eta(bytes_copied: u64, bytes_remaining: u64, elapsed: Duration) -> Option<Duration> {
+    if bytes_copied == 0 {
+        return None;
+    }
+    let bytes_per_sec = bytes_copied as f64 / elapsed.as_secs_f64();
+    let secs = bytes_remaining as f64 / bytes_per_sec;
+    if secs.is_finite() && secs >= 0.0 && secs < u64::MAX as f64 {
+        Some(Duration::from_secs_f64(secs))
+    } else {
+        None
+    }
+}
+
+// Returns the number of files and directories inside the directory, or zero if it can't be listed.
+fn count_entries(dir: &Path) -> u64 {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+    entries.filter_map(|entry| entry.ok()).map(|entry| {
+        let is_dir = entry.file_type().map(|t| t.is_dir()).unwrap_or(false);
+        1 + if is_dir { count_entries(&entry.path()) } else { 0 }
+    }).sum()
+}
+
+// Returns the number of files and directories in the destination that the operation deletes,
+// including a directory that it replaces.
+fn deleted_entries(op: &IoOperation) -> u64 {
+    let replaced = match *op {
+        IoOperation::DeleteDirAll(ref dir) => return 1 + count_entries(dir),
+        IoOperation::DeleteFile(_) => return 1,
+        IoOperation::CopyFileIfNeeded(ref data) => (&data.dest, &data.dest_meta),
+        IoOperation::CopySymlink(ref data) => (&data.dest, &data.dest_meta),
+        IoOperation::HardLink(_) => return 0,
+    };
+    match replaced {
+        (dest, &Some(ref dest_meta)) if dest_meta.is_dir() => 1 + count_entries(dest),
+        _ => 0,
+    }
+}
+
+// Deletes a file or a symlink. On Windows, a symlink to a directory has to be deleted as a
+// directory.
+#[cfg(windows)]
+fn remove_file_or_link(path: &Path) -> io::Result<()> {
+    fs::remove_file(path).or_else(|err| {
+        match fs::symlink_metadata(path) {
+            Ok(ref meta) if meta.file_type().is_symlink() => fs::remove_dir(path),
+            _ => Err(err),
+        }
+    })
+}
+
+#[cfg(not(windows))]
+fn remove_file_or_link(path: &Path) -> io::Result<()> {
+    fs::remove_file(path)
+}
+
+#[cfg(unix)]
+fn create_symlink(target: &Path, path: &Path, _is_dir: bool) -> io::Result<()> {
+    ::std::os::unix::fs::symlink(target, path)
+}
+
+#[cfg(windows)]
+fn create_symlink(target: &Path, path: &Path, is_dir: bool) -> io::Result<()> {
+    if is_dir {
+        ::std::os::windows::fs::symlink_dir(target, path)
+    } else {
+        ::std::os::windows::fs::symlink_file(target, path)
+    }
+}
+
+// Returns the device and inode of a file that has more than one hard link.
+#[cfg(unix)]
+fn hard_link_id(meta: &Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    if meta.nlink() > 1 { Some((meta.dev(), meta.ino())) } else { None }
+}
+
+#[cfg(not(unix))]
+fn hard_link_id(_meta: &Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+#[cfg(unix)]
+fn is_same_file(meta1: &Metadata, meta2: &Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    meta1.dev() == meta2.dev() && meta1.ino() == meta2.ino()
+}
+
+#[cfg(not(unix))]
+fn is_same_file(_meta1: &Metadata, _meta2: &Metadata) -> bool {
+    false
+}
+
+// Reads until the buffer is full or the end of the file is reached. Returns the number of bytes
+// read, which is only less than the buffer length at the end of the file.
+fn read_chunk(file: &mut File, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut total = 0;
+    while total < buffer.len() {
+        match file.read(&mut buffer[total..]) {
+            Ok(0) => break,
+            Ok(size) => total += size,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {},
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(total)
+}
+
+// The rules from the filter files in a directory, linked to the rules from its ancestors.
+struct DirFilters {
+    parent: Option<Arc<DirFilters>>,
+    dir: PathBuf,
+    rules: FilterRules,
+}
+
+impl DirFilters {
+    fn is_included(&self, path: &Path, is_dir: bool) -> bool {
+        let mut dir_filters = Some(self);
+        while let Some(filters) = dir_filters {
+            if let Ok(rel_path) = path.strip_prefix(&filters.dir) {
+                if let Some(rule) = filters.rules.matching_rule(rel_path, is_dir) {
+                    return rule.kind() == RuleKind::Include;
+                }
+            }
+            dir_filters = filters.parent.as_ref().map(|parent| &**parent);
+        }
+        true
+    }
+
+    fn has_protect_rules(&self) -> bool {
+        let mut dir_filters = Some(self);
+        while let Some(filters) = dir_filters {
+            if filters.rules.rules().iter().any(|rule| rule.kind() == RuleKind::Protect) {
+                return true;
+            }
+            dir_filters = filters.parent.as_ref().map(|parent| &**parent);
+        }
+        false
+    }
+
+    fn is_protected(&self, path: &Path, is_dir: bool) -> bool {
+        let mut dir_filters = Some(self);
+        while let Some(filters) = dir_filters {
+            if let Ok(rel_path) = path.strip_prefix(&filters.dir) {
+                if filters.rules.is_protected(rel_path, is_dir) {
+                    return true;
+                }
+            }
+            dir_filters = filters.parent.as_ref().map(|parent| &**parent);
+        }
+        false
+    }
+}
+
+// The real paths of the source directories on the way to a directory, linked like `DirFilters`.
+// They are only kept when following symlinks.
+struct AncestorDirs {
+    parent: Option<Arc<AncestorDirs>>,
+    dir: PathBuf,
+}
+
+impl AncestorDirs {
+    // Returns true if any of the directories is `dir` or inside it.
+    fn any_inside(&self, dir: &Path) -> bool {
+        let mut ancestors = Some(self);
+        while let Some(ancestor) = ancestors {
+            if ancestor.dir.starts_with(dir) {
+                return true;
+            }
+            ancestors = ancestor.parent.as_ref().map(|parent| &**parent);
+        }
+        false
+    }
+}
+
+struct CopyFileIfNeededData {
+        pub src: PathBuf,
+        pub dest: PathBuf,
+        pub src_meta: Metadata,
+        pub dest_meta: Option<Metadata>,
+    }
+
+// A directory whose permissions or extended attributes are set at the end of the sync.
+struct DirAttributes {
+    dest: PathBuf,
+    src_meta: Metadata,
+    // Set if the permissions should be copied.
+    permissions: bool,
+    // Set if the directory was made writable while it was synced, so its mode has to be restored.
+    made_writable: bool,
+    // The extended attributes of the source, if they should be copied.
+    xattrs: Option<Vec<(OsString, Vec<u8>)>>,
+}
+
+struct HardLinkData {
+    src: PathBuf,
+    dest: PathBuf,
+    // The destination of the file in the source that `src` is linked to.
+    target: PathBuf,
+    src_meta: Metadata,
+}
+
+struct CopySymlinkData {
+    src: PathBuf,
+    dest: PathBuf,
+    target: PathBuf,
+    dest_meta: Option<Metadata>,
+}
+
+enum IoOperation {
+    DeleteDirAll(PathBuf),
+    DeleteFile(PathBuf),
+    CopyFileIfNeeded(CopyFileIfNeededData),
+    CopySymlink(CopySymlinkData),
+    HardLink(HardLinkData),
+}
+
+/// Why a file's contents were copied to the destination.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CopyReason {
+    Missing,
+    DateMismatched,
+    SizeMismatched,
+    StartEndMismatched,
+    ContentsMismatched,
+    None,
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::ffi::OsStr;
+    use std::fs::{self, File, Metadata};
+    use std::io::{self, Read, Write};
+    use std::path::Path;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::{Duration, SystemTime};
+    use archive;
+    use file_times;
+    use filter::{FilterRule, FilterRules};
+    use super::{CopyReason, DeletionGuard, PairReport, PlannedOperation, RootProblem, SkipReason,
+                SymlinkPolicy, SyncBuilder, SyncErrorOp, SyncEvent, SyncFailure, estimate_eta};
+
+    fn read_file<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, io::Error> {
+        let mut f = File::open(path)?;
+        let metadata = f.metadata()?;
+        let mut data = Vec::with_capacity(metadata.len() as usize);
+        f.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn write_file<P: AsRef<Path>>(path: P, data: &[u8]) -> io::Result<()> {
+        let mut file = fs::File::create(path)?;
+        file.write_all(data)?;
+        Ok(())
+    }
+
+    fn list_dir<P: AsRef<Path>>(path: P) -> Result<Vec<String>, io::Error> {
+        let entries = fs::read_dir(path)?;
+        let mut entry_info = vec![];
+        for entry in entries {
+            let entry = entry?;
+            let meta = entry.metadata()?;
+            let data = if meta.is_file() {
+                Some(read_file(entry.path())?)
+            } else {
+                None
+            };
+            entry_info.push((meta, entry.file_name(), data));
+        }
+        entry_info.sort_by(|&(_, ref file_name1, _), &(_, ref file_name2, _)| file_name1.cmp(file_name2));
+        Ok(entry_info.iter().map(|&(ref metadata, ref file_name, ref data)| {
+            format!("{}:{}:{}", if metadata.is_dir() { "D" } else { "F" }, file_name.to_string_lossy(),
+                    data.as_ref().map(|d| String::from_utf8_lossy(d)).unwrap_or("".into()))
+        }).collect())
+    }
+
+    #[test]
+    fn test_basic_sync() {
+        let temp_dir = env::temp_dir();
+        let src_dir = temp_dir.join("SyncBuilderTestsSource");
+        let _ = fs::remove_dir_all(&src_dir);
+        fs::create_dir(&src_dir).expect("failed to create SyncBuilderTestsSource");
+        let dest_dir = temp_dir.join("SyncBuilderTestsDest");
+        let _ = fs::remove_dir_all(&dest_dir);
+        fs::create_dir(&dest_dir).expect("failed to create SyncBuilderTestsDest");
+
+        write_file(src_dir.join("banana.txt"), b"cd").expect("failed to create banana.txt");
+        write_file(src_dir.join("cherry.txt"), b"de").expect("failed to create cherry.txt");
+        write_file(src_dir.join("grape.txt"), b"hi").expect("failed to create grape.txt");
+        fs::create_dir(src_dir.join("peach.txt")).expect("failed to create peach.txt");
+
+        write_file(dest_dir.join("apple.txt"), b"bc").expect("failed to create apple.txt");
+        fs::create_dir(dest_dir.join("cherry.txt")).expect("failed to create cherry.txt");
+        write_file(dest_dir.join("grape.txt"), b"hij").expect("failed to create grape.txt");
+        write_file(dest_dir.join("peach.txt"), b"qr").expect("failed to create peach.txt");
+
+        let op = SyncBuilder::new().add_directory_pair(src_dir.clone(), dest_dir.clone()).sync();
+        op.wait();
+
+        let dest_list = list_dir(&dest_dir).expect("failed to list dir");
+        assert_eq!(dest_list, &[
+            "F:banana.txt:cd",
+            "F:cherry.txt:de",
+            "F:grape.txt:hi",
+            "D:peach.txt:",
         ]);
 
         let _ = fs::remove_dir_all(&src_dir).expect("failed to delete SyncBuilderTestsSource");
         let _ = fs::remove_dir_all(&dest_dir).expect("failed to delete SyncBuilderTestsDest");
     }
+
+    #[test]
+    fn test_contents_mismatched_sync() {
+        let temp_dir = env::temp_dir();
+        let src_dir = temp_dir.join("SyncBuilderContentsTestsSource");
+        let _ = fs::remove_dir_all(&src_dir);
+        fs::create_dir(&src_dir).expect("failed to create SyncBuilderContentsTestsSource");
+        let dest_dir = temp_dir.join("SyncBuilderContentsTestsDest");
+        let _ = fs::remove_dir_all(&dest_dir);
+        fs::create_dir(&dest_dir).expect("failed to create SyncBuilderContentsTestsDest");
+
+        // The files are the same size and have the same start and end, so only a full comparison
+        // will notice the difference in the middle.
+        let mut data = vec![b'a'; 1024 * 1024];
+        write_file(src_dir.join("large.bin"), &data).expect("failed to create large.bin");
+        data[512 * 1024] = b'b';
+        write_file(dest_dir.join("large.bin"), &data).expect("failed to create large.bin");
+
+        let op = SyncBuilder::new().add_directory_pair(src_dir.clone(), dest_dir.clone()).sync();
+        while !op.is_done() {
+            thread::sleep(Duration::from_millis(100));
+        }
+        assert_eq!(read_file(dest_dir.join("large.bin")).expect("failed to read large.bin"),
+                   data);
+
+        let op = SyncBuilder::new()
+                 .copy_contents_if_contents_mismatched(true)
+                 .add_directory_pair(src_dir.clone(), dest_dir.clone())
+                 .sync();
+        while !op.is_done() {
+            thread::sleep(Duration::from_millis(100));
+        }
+        assert_eq!(read_file(dest_dir.join("large.bin")).expect("failed to read large.bin"),
+                   vec![b'a'; 1024 * 1024]);
+
+        let _ = fs::remove_dir_all(&src_dir).expect("failed to delete SyncBuilderContentsTestsSource");
+        let _ = fs::remove_dir_all(&dest_dir).expect("failed to delete SyncBuilderContentsTestsDest");
+    }
+
+    #[test]
+    fn test_modified_date_sync() {
+        let temp_dir = env::temp_dir();
+        let src_dir = temp_dir.join("SyncBuilderDateTestsSource");
+        let _ = fs::remove_dir_all(&src_dir);
+        fs::create_dir(&src_dir).expect("failed to create SyncBuilderDateTestsSource");
+        let dest_dir = temp_dir.join("SyncBuilderDateTestsDest");
+        let _ = fs::remove_dir_all(&dest_dir);
+        fs::create_dir(&dest_dir).expect("failed to create SyncBuilderDateTestsDest");
+
+        write_file(src_dir.join("banana.txt"), b"cd").expect("failed to create banana.txt");
+        let modified = SystemTime::now() - Duration::from_secs(60 * 60 * 24 * 30);
+        file_times::set_modified(src_dir.join("banana.txt"), modified)
+            .expect("failed to set modified date of banana.txt");
+
+        let op = SyncBuilder::new().add_directory_pair(src_dir.clone(), dest_dir.clone()).sync();
+        while !op.is_done() {
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        let dest_meta = fs::metadata(dest_dir.join("banana.txt")).expect("failed to stat banana.txt");
+        assert_eq!(dest_meta.modified().expect("failed to get modified date"), modified);
+
+        let _ = fs::remove_dir_all(&src_dir).expect("failed to delete SyncBuilderDateTestsSource");
+        let _ = fs::remove_dir_all(&dest_dir).expect("failed to delete SyncBuilderDateTestsDest");
+    }
+
+    #[test]
+    fn test_plan() {
+        let temp_dir = env::temp_dir();
+        let src_dir = temp_dir.join("SyncBuilderPlanTestsSource");
+        let _ = fs::remove_dir_all(&src_dir);
+        fs::create_dir(&src_dir).expect("failed to create SyncBuilderPlanTestsSource");
+        let dest_dir = temp_dir.join("SyncBuilderPlanTestsDest");
+        let _ = fs::remove_dir_all(&dest_dir);
+        fs::create_dir(&dest_dir).expect("failed to create SyncBuilderPlanTestsDest");
+
+        write_file(src_dir.join("banana.txt"), b"cd").expect("failed to create banana.txt");
+        write_file(src_dir.join("grape.txt"), b"hi").expect("failed to create grape.txt");
+        fs::create_dir(src_dir.join("peach.txt")).expect("failed to create peach.txt");
+        write_file(src_dir.join("peach.txt").join("pit.txt"), b"p").expect("failed to create pit.txt");
+
+        write_file(dest_dir.join("apple.txt"), b"bc").expect("failed to create apple.txt");
+        fs::create_dir(dest_dir.join("cherry.txt")).expect("failed to create cherry.txt");
+        write_file(dest_dir.join("grape.txt"), b"hij").expect("failed to create grape.txt");
+
+        let op = SyncBuilder::new().add_directory_pair(src_dir.clone(), dest_dir.clone()).plan();
+        while !op.is_done() {
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        let mut plan = vec![];
+        while let Some(planned) = op.read_plan() {
+            plan.push(planned);
+        }
+        plan.sort_by_key(|planned| format!("{:?}", planned));
+        assert_eq!(plan, &[
+            PlannedOperation::CopyFile {
+                src: src_dir.join("banana.txt"),
+                dest: dest_dir.join("banana.txt"),
+                reason: CopyReason::Missing,
+            },
+            PlannedOperation::CopyFile {
+                src: src_dir.join("grape.txt"),
+                dest: dest_dir.join("grape.txt"),
+                reason: CopyReason::SizeMismatched,
+            },
+            PlannedOperation::CopyFile {
+                src: src_dir.join("peach.txt").join("pit.txt"),
+                dest: dest_dir.join("peach.txt").join("pit.txt"),
+                reason: CopyReason::Missing,
+            },
+            PlannedOperation::CreateDir(dest_dir.join("peach.txt")),
+            PlannedOperation::DeleteDirAll(dest_dir.join("cherry.txt")),
+            PlannedOperation::DeleteFile(dest_dir.join("apple.txt")),
+        ]);
+
+        // Nothing in the destination should have changed.
+        let dest_list = list_dir(&dest_dir).expect("failed to list dir");
+        assert_eq!(dest_list, &[
+            "F:apple.txt:bc",
+            "D:cherry.txt:",
+            "F:grape.txt:hij",
+        ]);
+
+        let _ = fs::remove_dir_all(&src_dir).expect("failed to delete SyncBuilderPlanTestsSource");
+        let _ = fs::remove_dir_all(&dest_dir).expect("failed to delete SyncBuilderPlanTestsDest");
+    }
+
+    #[test]
+    fn test_cancel() {
+        let temp_dir = env::temp_dir();
+        let src_dir = temp_dir.join("SyncBuilderCancelTestsSource");
+        let _ = fs::remove_dir_all(&src_dir);
+        fs::create_dir(&src_dir).expect("failed to create SyncBuilderCancelTestsSource");
+        let dest_dir = temp_dir.join("SyncBuilderCancelTestsDest");
+        let _ = fs::remove_dir_all(&dest_dir);
+        fs::create_dir(&dest_dir).expect("failed to create SyncBuilderCancelTestsDest");
+
+        write_file(src_dir.join("banana.txt"), b"cd").expect("failed to create banana.txt");
+
+        let mut builder = SyncBuilder::new();
+        builder.add_directory_pair(src_dir.clone(), dest_dir.clone());
+        let op = super::SyncOperation::new(&builder);
+        op.pause();
+        op.cancel();
+        op.run();
+        assert!(op.is_done());
+        assert!(op.is_cancelled());
+        assert!(!op.is_paused());
+
+        let dest_list = list_dir(&dest_dir).expect("failed to list dir");
+        assert!(dest_list.is_empty());
+
+        let _ = fs::remove_dir_all(&src_dir).expect("failed to delete SyncBuilderCancelTestsSource");
+        let _ = fs::remove_dir_all(&dest_dir).expect("failed to delete SyncBuilderCancelTestsDest");
+    }
+
+    #[test]
+    fn test_pause_during_copy() {
+        let temp_dir = env::temp_dir();
+        let src_dir = temp_dir.join("SyncBuilderPauseTestsSource");
+        let _ = fs::remove_dir_all(&src_dir);
+        fs::create_dir(&src_dir).expect("failed to create SyncBuilderPauseTestsSource");
+        let dest_dir = temp_dir.join("SyncBuilderPauseTestsDest");
+        let _ = fs::remove_dir_all(&dest_dir);
+        fs::create_dir(&dest_dir).expect("failed to create SyncBuilderPauseTestsDest");
+
+        // The file is large enough that the copy is still going when it is paused.
+        const LEN: usize = 64 * 1024 * 1024;
+        write_file(src_dir.join("big.bin"), &vec![b'a'; LEN]).expect("failed to create big.bin");
+        write_file(dest_dir.join("big.bin"), b"old").expect("failed to create big.bin");
+
+        // Pauses the operation as soon as the copy starts.
+        let start_paused = || {
+            let op = SyncBuilder::new()
+                     .add_directory_pair(src_dir.clone(), dest_dir.clone())
+                     .sync();
+            let receiver = op.log_receiver();
+            for entry in receiver.iter() {
+                if let SyncEvent::CopyStarted { .. } = entry.event {
+                    op.pause();
+                    break;
+                }
+            }
+            (op, receiver)
+        };
+
+        // Cancelling while paused leaves the destination file as it was.
+        let (op, receiver) = start_paused();
+        thread::sleep(Duration::from_millis(100));
+        let progress = op.progress();
+        thread::sleep(Duration::from_millis(200));
+        assert!(op.is_paused());
+        assert!(!op.is_done());
+        assert_eq!(op.progress().bytes_copied, progress.bytes_copied);
+        assert!(progress.bytes_copied < LEN as u64);
+        assert_eq!(progress.in_flight, &[Some(src_dir.join("big.bin"))]);
+        assert_eq!(read_file(dest_dir.join("big.bin")).expect("failed to read big.bin"), b"old");
+        op.cancel();
+        let report = op.wait();
+        assert!(report.cancelled);
+        assert_eq!(report.files_copied, 0);
+        assert_eq!(report.errors, 0);
+        assert!(receiver.iter().any(|entry| match entry.event {
+            SyncEvent::CopyCancelled { .. } => true,
+            _ => false,
+        }));
+        assert_eq!(read_file(dest_dir.join("big.bin")).expect("failed to read big.bin"), b"old");
+        assert_eq!(fs::read_dir(&dest_dir).expect("failed to list dir").count(), 1);
+
+        // Resuming finishes the copy.
+        let (op, _receiver) = start_paused();
+        thread::sleep(Duration::from_millis(100));
+        assert!(!op.is_done());
+        op.resume();
+        let report = op.wait();
+        assert!(!report.cancelled);
+        assert_eq!(report.files_copied, 1);
+        assert_eq!(report.bytes_copied, LEN as u64);
+        let dest_contents = read_file(dest_dir.join("big.bin")).expect("failed to read big.bin");
+        assert!(dest_contents.len() == LEN && dest_contents.iter().all(|&b| b == b'a'));
+
+        // A file that gets shorter while it is copied isn't copied.
+        write_file(dest_dir.join("big.bin"), b"old").expect("failed to create big.bin");
+        let (op, _receiver) = start_paused();
+        op.resume();
+        while op.progress().bytes_copied == 0 {
+            thread::yield_now();
+        }
+        op.pause();
+        write_file(src_dir.join("big.bin"), b"").expect("failed to create big.bin");
+        assert!(op.progress().bytes_copied < LEN as u64);
+        op.resume();
+        let report = op.wait();
+        assert_eq!(report.files_copied, 0);
+        assert_eq!(report.errors, 1);
+        assert_eq!(report.failures[0].kind, io::ErrorKind::UnexpectedEof);
+        assert_eq!(read_file(dest_dir.join("big.bin")).expect("failed to read big.bin"), b"old");
+        assert_eq!(fs::read_dir(&dest_dir).expect("failed to list dir").count(), 1);
+
+        let _ = fs::remove_dir_all(&src_dir).expect("failed to delete SyncBuilderPauseTestsSource");
+        let _ = fs::remove_dir_all(&dest_dir).expect("failed to delete SyncBuilderPauseTestsDest");
+    }
+
+    #[test]
+    fn test_progress() {
+        let temp_dir = env::temp_dir();
+        let src_dir = temp_dir.join("SyncBuilderProgressTestsSource");
+        let _ = fs::remove_dir_all(&src_dir);
+        fs::create_dir(&src_dir).expect("failed to create SyncBuilderProgressTestsSource");
+        let dest_dir = temp_dir.join("SyncBuilderProgressTestsDest");
+        let _ = fs::remove_dir_all(&dest_dir);
+        fs::create_dir(&dest_dir).expect("failed to create SyncBuilderProgressTestsDest");
+
+        write_file(src_dir.join("banana.txt"), b"cd").expect("failed to create banana.txt");
+        write_file(src_dir.join("cherry.txt"), b"def").expect("failed to create cherry.txt");
+        fs::create_dir(src_dir.join("peach")).expect("failed to create peach");
+        write_file(dest_dir.join("apple.txt"), b"bc").expect("failed to create apple.txt");
+        write_file(dest_dir.join("cherry.txt"), b"def").expect("failed to create cherry.txt");
+
+        let op = SyncBuilder::new().add_directory_pair(src_dir.clone(), dest_dir.clone()).sync();
+        while !op.is_done() {
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        let progress = op.progress();
+        assert_eq!(progress.dirs_scanned, 2);
+        assert_eq!(progress.files_compared, 2);
+        assert_eq!(progress.files_copied, 1);
+        assert_eq!(progress.files_skipped, 1);
+        assert_eq!(progress.files_deleted, 1);
+        assert_eq!(progress.dirs_deleted, 0);
+        assert_eq!(progress.bytes_copied, 2);
+        assert_eq!(progress.bytes_remaining, 0);
+        assert_eq!(progress.errors, 0);
+        assert_eq!(progress.in_flight, &[None]);
+        assert_eq!(progress.eta, Some(Duration::from_secs(0)));
+        // A rate too slow to give an estimate that fits in a Duration gives none.
+        assert_eq!(estimate_eta(1, 1 << 62, Duration::from_secs(1_000_000)), None);
+        assert_eq!(estimate_eta(1, 1, Duration::from_secs(0)), Some(Duration::from_secs(0)));
+
+        let _ = fs::remove_dir_all(&src_dir).expect("failed to delete SyncBuilderProgressTestsSource");
+        let _ = fs::remove_dir_all(&dest_dir).expect("failed to delete SyncBuilderProgressTestsDest");
+    }
+
+    #[test]
+    fn test_events() {
+        let temp_dir = env::temp_dir();
+        let src_dir = temp_dir.join("SyncBuilderEventsTestsSource");
+        let _ = fs::remove_dir_all(&src_dir);
+        fs::create_dir(&src_dir).expect("failed to create SyncBuilderEventsTestsSource");
+        let dest_dir = temp_dir.join("SyncBuilderEventsTestsDest");
+        let _ = fs::remove_dir_all(&dest_dir);
+        fs::create_dir(&dest_dir).expect("failed to create SyncBuilderEventsTestsDest");
+
+        write_file(src_dir.join("banana.txt"), b"cd").expect("failed to create banana.txt");
+        write_file(src_dir.join("grape.wav"), b"hi").expect("failed to create grape.wav");
+        write_file(dest_dir.join("apple.txt"), b"bc").expect("failed to create apple.txt");
+
+        let op = SyncBuilder::new()
+                 .add_directory_pair(src_dir.clone(), dest_dir.clone())
+                 .filter(|path, _| path.extension().map_or(true, |ext| ext != "wav"))
+                 .sync();
+        while !op.is_done() {
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        let mut events = vec![];
+        while let Some(entry) = op.read_log() {
+            assert_eq!(entry.message, entry.event.to_string());
+            match entry.event {
+                SyncEvent::DirScanned { .. } | SyncEvent::ThreadExited { .. } => {},
+                event => events.push(event),
+            }
+        }
+        events.sort_by_key(|event| format!("{:?}", event));
+        assert_eq!(events, &[
+            SyncEvent::CopyFinished {
+                src: src_dir.join("banana.txt"),
+                dest: dest_dir.join("banana.txt"),
+                size: 2,
+            },
+            SyncEvent::CopyStarted {
+                src: src_dir.join("banana.txt"),
+                dest: dest_dir.join("banana.txt"),
+                reason: CopyReason::Missing,
+                size: 2,
+            },
+            SyncEvent::Deleted { path: dest_dir.join("apple.txt"), is_dir: false },
+            SyncEvent::Skipped { path: src_dir.join("grape.wav"), reason: SkipReason::Filter },
+        ]);
+
+        let _ = fs::remove_dir_all(&src_dir).expect("failed to delete SyncBuilderEventsTestsSource");
+        let _ = fs::remove_dir_all(&dest_dir).expect("failed to delete SyncBuilderEventsTestsDest");
+    }
+
+    #[test]
+    fn test_stale_temp_file_deleted() {
+        let temp_dir = env::temp_dir();
+        let src_dir = temp_dir.join("SyncBuilderTempTestsSource");
+        let _ = fs::remove_dir_all(&src_dir);
+        fs::create_dir(&src_dir).expect("failed to create SyncBuilderTempTestsSource");
+        let dest_dir = temp_dir.join("SyncBuilderTempTestsDest");
+        let _ = fs::remove_dir_all(&dest_dir);
+        fs::create_dir(&dest_dir).expect("failed to create SyncBuilderTempTestsDest");
+
+        write_file(src_dir.join("banana.txt"), b"cd").expect("failed to create banana.txt");
+        write_file(dest_dir.join("banana.txt"), b"c").expect("failed to create banana.txt");
+        write_file(dest_dir.join(".banana.txt.1234.mirror-sync-tmp"), b"")
+            .expect("failed to create .banana.txt.1234.mirror-sync-tmp");
+        // Files that only look like temporary files are synced like any others.
+        write_file(src_dir.join(".cherry.txt.mirror-sync-tmp"), b"e")
+            .expect("failed to create .cherry.txt.mirror-sync-tmp");
+        let grape_temp = ".grape.txt.1234.mirror-sync-tmp";
+        write_file(dest_dir.join(grape_temp), b"f").expect("failed to create grape temp file");
+
+        let op = SyncBuilder::new()
+                 .add_directory_pair(src_dir.clone(), dest_dir.clone())
+                 .protect(move |path: &Path, _: &Metadata| path.ends_with(grape_temp))
+                 .sync();
+        while !op.is_done() {
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        assert_eq!(op.progress().files_deleted, 1);
+        let dest_list = list_dir(&dest_dir).expect("failed to list dir");
+        assert_eq!(dest_list, &[
+            "F:.cherry.txt.mirror-sync-tmp:e",
+            "F:.grape.txt.1234.mirror-sync-tmp:f",
+            "F:banana.txt:cd",
+        ]);
+
+        let _ = fs::remove_dir_all(&src_dir).expect("failed to delete SyncBuilderTempTestsSource");
+        let _ = fs::remove_dir_all(&dest_dir).expect("failed to delete SyncBuilderTempTestsDest");
+    }
+
+    #[test]
+    fn test_filter_files() {
+        let temp_dir = env::temp_dir();
+        let src_dir = temp_dir.join("SyncBuilderFilterFileTestsSource");
+        let _ = fs::remove_dir_all(&src_dir);
+        fs::create_dir(&src_dir).expect("failed to create SyncBuilderFilterFileTestsSource");
+        let dest_dir = temp_dir.join("SyncBuilderFilterFileTestsDest");
+        let _ = fs::remove_dir_all(&dest_dir);
+        fs::create_dir(&dest_dir).expect("failed to create SyncBuilderFilterFileTestsDest");
+
+        write_file(src_dir.join(".mirrorignore"), b"# logs\n*.log\n")
+            .expect("failed to create .mirrorignore");
+        write_file(src_dir.join("a.log"), b"a").expect("failed to create a.log");
+        fs::create_dir(src_dir.join("sub")).expect("failed to create sub");
+        write_file(src_dir.join("sub/.mirrorignore"), b"!keep.log\ntarget/\n")
+            .expect("failed to create sub/.mirrorignore");
+        write_file(src_dir.join("sub/b.log"), b"b").expect("failed to create sub/b.log");
+        write_file(src_dir.join("sub/keep.log"), b"k").expect("failed to create sub/keep.log");
+        fs::create_dir(src_dir.join("sub/target")).expect("failed to create sub/target");
+        write_file(src_dir.join("sub/target/out"), b"o").expect("failed to create sub/target/out");
+        write_file(dest_dir.join("a.log"), b"old").expect("failed to create a.log");
+
+        let op = SyncBuilder::new().add_directory_pair(src_dir.clone(), dest_dir.clone()).sync();
+        while !op.is_done() {
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        let dest_list = list_dir(&dest_dir).expect("failed to list dir");
+        assert_eq!(dest_list, &[
+            "F:.mirrorignore:# logs\n*.log\n",
+            "D:sub:",
+        ]);
+        let dest_list = list_dir(dest_dir.join("sub")).expect("failed to list dir");
+        assert_eq!(dest_list, &[
+            "F:.mirrorignore:!keep.log\ntarget/\n",
+            "F:keep.log:k",
+        ]);
+
+        let _ = fs::remove_dir_all(&src_dir).expect("failed to delete SyncBuilderFilterFileTestsSource");
+        let _ = fs::remove_dir_all(&dest_dir).expect("failed to delete SyncBuilderFilterFileTestsDest");
+    }
+
+    #[test]
+    fn test_protect() {
+        let temp_dir = env::temp_dir();
+        let src_dir = temp_dir.join("SyncBuilderProtectTestsSource");
+        let _ = fs::remove_dir_all(&src_dir);
+        fs::create_dir(&src_dir).expect("failed to create SyncBuilderProtectTestsSource");
+        let dest_dir = temp_dir.join("SyncBuilderProtectTestsDest");
+        let _ = fs::remove_dir_all(&dest_dir);
+        fs::create_dir(&dest_dir).expect("failed to create SyncBuilderProtectTestsDest");
+
+        write_file(src_dir.join(".mirrorignore"), b"P local.conf\n")
+            .expect("failed to create .mirrorignore");
+        write_file(src_dir.join("a.tmp"), b"a").expect("failed to create a.tmp");
+        write_file(dest_dir.join(".backup-marker"), b"m").expect("failed to create .backup-marker");
+        write_file(dest_dir.join("a.tmp"), b"a").expect("failed to create a.tmp");
+        write_file(dest_dir.join("b.tmp"), b"b").expect("failed to create b.tmp");
+        write_file(dest_dir.join("local.conf"), b"c").expect("failed to create local.conf");
+
+        let mut rules = FilterRules::new();
+        rules.push(FilterRule::exclude("*.tmp")).push(FilterRule::protect(".backup-marker"))
+             .push(FilterRule::protect("a.tmp"));
+        let op = SyncBuilder::new()
+                 .filter_rules(&rules)
+                 .add_directory_pair(src_dir.clone(), dest_dir.clone())
+                 .sync();
+        while !op.is_done() {
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        let dest_list = list_dir(&dest_dir).expect("failed to list dir");
+        assert_eq!(dest_list, &[
+            "F:.backup-marker:m",
+            "F:.mirrorignore:P local.conf\n",
+            "F:a.tmp:a",
+            "F:local.conf:c",
+        ]);
+
+        let _ = fs::remove_dir_all(&src_dir).expect("failed to delete SyncBuilderProtectTestsSource");
+        let _ = fs::remove_dir_all(&dest_dir).expect("failed to delete SyncBuilderProtectTestsDest");
+    }
+
+    #[test]
+    fn test_protect_nested() {
+        let temp_dir = env::temp_dir();
+        let src_dir = temp_dir.join("SyncBuilderProtectNestedTestsSource");
+        let _ = fs::remove_dir_all(&src_dir);
+        fs::create_dir(&src_dir).expect("failed to create SyncBuilderProtectNestedTestsSource");
+        let dest_dir = temp_dir.join("SyncBuilderProtectNestedTestsDest");
+        let _ = fs::remove_dir_all(&dest_dir);
+        fs::create_dir(&dest_dir).expect("failed to create SyncBuilderProtectNestedTestsDest");
+
+        fs::create_dir_all(dest_dir.join("old/sub/deeper"))
+            .expect("failed to create old/sub/deeper");
+        write_file(dest_dir.join("old/a.txt"), b"a").expect("failed to create old/a.txt");
+        write_file(dest_dir.join("old/sub/.backup-marker"), b"m")
+            .expect("failed to create old/sub/.backup-marker");
+        write_file(dest_dir.join("old/sub/b.txt"), b"b").expect("failed to create old/sub/b.txt");
+        write_file(dest_dir.join("old/sub/deeper/c.txt"), b"c")
+            .expect("failed to create old/sub/deeper/c.txt");
+        fs::create_dir(dest_dir.join("gone")).expect("failed to create gone");
+        write_file(dest_dir.join("gone/d.txt"), b"d").expect("failed to create gone/d.txt");
+
+        let mut rules = FilterRules::new();
+        rules.push(FilterRule::protect(".backup-marker"));
+        let report = SyncBuilder::new()
+                     .add_directory_pair(src_dir.clone(), dest_dir.clone())
+                     .filter_rules(&rules)
+                     .refuse_empty_source(false)
+                     .sync()
+                     .wait();
+        assert_eq!(report.errors, 0);
+        assert_eq!(report.files_deleted, 2);
+        assert_eq!(report.dirs_deleted, 2);
+
+        assert_eq!(list_dir(&dest_dir).expect("failed to list dir"), &["D:old:"]);
+        assert_eq!(list_dir(dest_dir.join("old")).expect("failed to list dir"), &["D:sub:"]);
+        assert_eq!(list_dir(dest_dir.join("old/sub")).expect("failed to list dir"),
+                   &["F:.backup-marker:m"]);
+
+        let _ = fs::remove_dir_all(&src_dir).expect("failed to delete SyncBuilderProtectNestedTestsSource");
+        let _ = fs::remove_dir_all(&dest_dir).expect("failed to delete SyncBuilderProtectNestedTestsDest");
+    }
+
+    #[test]
+    fn test_archive() {
+        let temp_dir = env::temp_dir();
+        let src_dir = temp_dir.join("SyncBuilderArchiveTestsSource");
+        let _ = fs::remove_dir_all(&src_dir);
+        fs::create_dir(&src_dir).expect("failed to create SyncBuilderArchiveTestsSource");
+        let dest_dir = temp_dir.join("SyncBuilderArchiveTestsDest");
+        let _ = fs::remove_dir_all(&dest_dir);
+        fs::create_dir(&dest_dir).expect("failed to create SyncBuilderArchiveTestsDest");
+        let archive_dir = temp_dir.join("SyncBuilderArchiveTestsArchive");
+        let _ = fs::remove_dir_all(&archive_dir);
+        let dest_archive_dir = archive_dir.join("SyncBuilderArchiveTestsDest");
+        fs::create_dir_all(&dest_archive_dir).expect("failed to create SyncBuilderArchiveTestsArchive");
+
+        write_file(src_dir.join("a.txt"), b"new").expect("failed to create a.txt");
+        write_file(dest_dir.join("a.txt"), b"old").expect("failed to create a.txt");
+        write_file(dest_dir.join("b.txt"), b"b").expect("failed to create b.txt");
+        fs::create_dir(dest_dir.join("old")).expect("failed to create old");
+        write_file(dest_dir.join("old/c.txt"), b"c").expect("failed to create old/c.txt");
+        // One version is too old to keep, and the other is one too many to keep.
+        let hour_ago = SystemTime::now() - Duration::from_secs(60 * 60);
+        write_file(dest_archive_dir.join(archive::version_name(OsStr::new("a.txt"), hour_ago)), b"")
+            .expect("failed to create archived a.txt");
+        write_file(dest_archive_dir.join("d.txt~20000101-000000"), b"")
+            .expect("failed to create archived d.txt");
+
+        let op = SyncBuilder::new()
+                 .add_directory_pair(src_dir.clone(), dest_dir.clone())
+                 .archive_dir(archive_dir.clone())
+                 .archive_keep_versions(1)
+                 .archive_keep_days(30)
+                 .sync();
+        while !op.is_done() {
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        let dest_list = list_dir(&dest_dir).expect("failed to list dir");
+        assert_eq!(dest_list, &[
+            "F:a.txt:new",
+        ]);
+        let archive_list: Vec<_> = list_dir(&dest_archive_dir).expect("failed to list dir")
+            .into_iter()
+            .map(|entry| {
+                let (name, content) = entry.split_at(entry.rfind(':').unwrap());
+                format!("{}{}", &name[..name.rfind('~').unwrap()], content)
+            })
+            .collect();
+        assert_eq!(archive_list, &[
+            "F:a.txt:old",
+            "F:b.txt:b",
+            "D:old:",
+        ]);
+        let progress = op.progress();
+        assert_eq!(progress.files_archived, 3);
+        assert_eq!(progress.errors, 0);
+        // The archived files and directories count as deleted in the pair too.
+        let report = op.wait();
+        assert_eq!((report.files_deleted, report.dirs_deleted), (1, 1));
+        assert_eq!((report.pairs[0].files_deleted, report.pairs[0].dirs_deleted), (1, 1));
+        assert_eq!(report.pairs[0].files_archived, report.files_archived);
+
+        // A file that is replaced by a directory is archived too.
+        write_file(dest_dir.join("e"), b"old").expect("failed to create e");
+        fs::create_dir(src_dir.join("e")).expect("failed to create e");
+        write_file(src_dir.join("e/f.txt"), b"f").expect("failed to create e/f.txt");
+        let report = SyncBuilder::new()
+                     .add_directory_pair(src_dir.clone(), dest_dir.clone())
+                     .archive_dir(archive_dir.clone())
+                     .sync()
+                     .wait();
+        assert_eq!(report.errors, 0);
+        assert_eq!(report.files_archived, 1);
+        assert_eq!((report.files_deleted, report.pairs[0].files_deleted), (1, 1));
+        assert_eq!(list_dir(dest_dir.join("e")).expect("failed to list dir"), &[
+            "F:f.txt:f",
+        ]);
+        let archived_e = list_dir(&dest_archive_dir).expect("failed to list dir").into_iter()
+            .any(|entry| entry.starts_with("F:e~") && entry.ends_with(":old"));
+        assert!(archived_e);
+
+        let _ = fs::remove_dir_all(&src_dir).expect("failed to delete SyncBuilderArchiveTestsSource");
+        let _ = fs::remove_dir_all(&dest_dir).expect("failed to delete SyncBuilderArchiveTestsDest");
+        let _ = fs::remove_dir_all(&archive_dir).expect("failed to delete SyncBuilderArchiveTestsArchive");
+    }
+
+    #[test]
+    fn test_deletion_guard() {
+        let temp_dir = env::temp_dir();
+        let src_dir = temp_dir.join("SyncBuilderGuardTestsSource");
+        let _ = fs::remove_dir_all(&src_dir);
+        fs::create_dir(&src_dir).expect("failed to create SyncBuilderGuardTestsSource");
+        let dest_dir = temp_dir.join("SyncBuilderGuardTestsDest");
+        let _ = fs::remove_dir_all(&dest_dir);
+        fs::create_dir(&dest_dir).expect("failed to create SyncBuilderGuardTestsDest");
+
+        write_file(dest_dir.join("apple.txt"), b"a").expect("failed to create apple.txt");
+        fs::create_dir(dest_dir.join("old")).expect("failed to create old");
+        write_file(dest_dir.join("old/banana.txt"), b"b").expect("failed to create banana.txt");
+
+        // The source is empty.
+        let op = SyncBuilder::new()
+                 .add_directory_pair(src_dir.clone(), dest_dir.clone())
+                 .refuse_empty_source(true)
+                 .sync();
+        while !op.is_done() {
+            thread::sleep(Duration::from_millis(100));
+        }
+        let mut refused = vec![];
+        while let Some(entry) = op.read_log() {
+            if let SyncEvent::DeletionsRefused { guard } = entry.event {
+                refused.push(guard);
+            }
+        }
+        assert_eq!(refused, &[DeletionGuard::EmptySource(src_dir.clone())]);
+        assert_eq!(op.progress().errors, 1);
+
+        // Three files and directories would be deleted, and one would be kept.
+        write_file(src_dir.join("apple.txt"), b"a").expect("failed to create apple.txt");
+        write_file(dest_dir.join("cherry.txt"), b"c").expect("failed to create cherry.txt");
+        let op = SyncBuilder::new()
+                 .add_directory_pair(src_dir.clone(), dest_dir.clone())
+                 .refuse_empty_source(true)
+                 .max_deleted_entries(2)
+                 .sync();
+        while !op.is_done() {
+            thread::sleep(Duration::from_millis(100));
+        }
+        let mut refused = vec![];
+        while let Some(entry) = op.read_log() {
+            if let SyncEvent::DeletionsRefused { guard } = entry.event {
+                refused.push(guard);
+            }
+        }
+        assert_eq!(refused, &[DeletionGuard::MaxEntries { entries: 3, max: 2 }]);
+
+        let op = SyncBuilder::new()
+                 .add_directory_pair(src_dir.clone(), dest_dir.clone())
+                 .max_deleted_entries(3)
+                 .max_deleted_percent(75)
+                 .sync();
+        while !op.is_done() {
+            thread::sleep(Duration::from_millis(100));
+        }
+        assert_eq!(op.progress().errors, 0);
+        let dest_list = list_dir(&dest_dir).expect("failed to list dir");
+        assert_eq!(dest_list, &[
+            "F:apple.txt:a",
+        ]);
+
+        // Replacing a directory with a file or a file with a directory deletes what was there, so
+        // it is counted, and nothing is replaced if it's refused.
+        write_file(src_dir.join("dir_to_file"), b"f").expect("failed to create dir_to_file");
+        fs::create_dir(src_dir.join("file_to_dir")).expect("failed to create file_to_dir");
+        write_file(src_dir.join("file_to_dir/x.txt"), b"x").expect("failed to create x.txt");
+        fs::create_dir(dest_dir.join("dir_to_file")).expect("failed to create dir_to_file");
+        write_file(dest_dir.join("dir_to_file/a.txt"), b"a").expect("failed to create a.txt");
+        write_file(dest_dir.join("dir_to_file/b.txt"), b"b").expect("failed to create b.txt");
+        write_file(dest_dir.join("file_to_dir"), b"old").expect("failed to create file_to_dir");
+        let sync = |max| {
+            SyncBuilder::new()
+                .add_directory_pair(src_dir.clone(), dest_dir.clone())
+                .max_deleted_entries(max)
+                .sync()
+                .wait()
+        };
+        let report = sync(3);
+        assert_eq!(report.deletions_refused,
+                   Some(DeletionGuard::MaxEntries { entries: 4, max: 3 }));
+        let dest_list = list_dir(&dest_dir).expect("failed to list dir");
+        assert_eq!(dest_list, &[
+            "F:apple.txt:a",
+            "D:dir_to_file:",
+            "F:file_to_dir:old",
+        ]);
+        let report = sync(4);
+        assert_eq!(report.errors, 0);
+        let dest_list = list_dir(&dest_dir).expect("failed to list dir");
+        assert_eq!(dest_list, &[
+            "F:apple.txt:a",
+            "F:dir_to_file:f",
+            "D:file_to_dir:",
+        ]);
+        let x = read_file(dest_dir.join("file_to_dir/x.txt")).expect("failed to read x.txt");
+        assert_eq!(x, b"x");
+
+        let _ = fs::remove_dir_all(&src_dir).expect("failed to delete SyncBuilderGuardTestsSource");
+        let _ = fs::remove_dir_all(&dest_dir).expect("failed to delete SyncBuilderGuardTestsDest");
+    }
+
+    #[test]
+    fn test_root_checks() {
+        let temp_dir = env::temp_dir();
+        let src_dir = temp_dir.join("SyncBuilderRootTestsSource");
+        let _ = fs::remove_dir_all(&src_dir);
+        fs::create_dir(&src_dir).expect("failed to create SyncBuilderRootTestsSource");
+        let dest_dir = temp_dir.join("SyncBuilderRootTestsDest");
+        let _ = fs::remove_dir_all(&dest_dir);
+        fs::create_dir(&dest_dir).expect("failed to create SyncBuilderRootTestsDest");
+        let missing_dir = temp_dir.join("SyncBuilderRootTestsMissing");
+        let _ = fs::remove_dir_all(&missing_dir);
+
+        write_file(src_dir.join("MARKER"), b"").expect("failed to create MARKER");
+
+        let op = SyncBuilder::new()
+                 .add_directory_pair(missing_dir.clone(), dest_dir.clone())
+                 .add_directory_pair(src_dir.clone(), src_dir.join("."))
+                 .add_directory_pair(src_dir.clone(), src_dir.join("MirrorSubdir"))
+                 .add_directory_pair(src_dir.join("MARKER"), dest_dir.clone())
+                 .add_directory_pair(src_dir.clone(), dest_dir.clone())
+                 .marker_file("MARKER")
+                 .sync();
+        while !op.is_done() {
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        let mut problems = vec![];
+        while let Some(entry) = op.read_log() {
+            if let SyncEvent::PairRejected { problem, .. } = entry.event {
+                problems.push(problem);
+            }
+        }
+        assert_eq!(problems, &[
+            RootProblem::Missing(missing_dir.clone()),
+            RootProblem::SamePath,
+            RootProblem::Missing(src_dir.join("MirrorSubdir")),
+            RootProblem::NotDirectory(src_dir.join("MARKER")),
+            RootProblem::MarkerMissing(dest_dir.join("MARKER")),
+        ]);
+        assert!(!missing_dir.exists());
+        assert!(!src_dir.join("MirrorSubdir").exists());
+        assert_eq!(list_dir(&dest_dir).expect("failed to list dir").len(), 0);
+
+        fs::create_dir(src_dir.join("MirrorSubdir")).expect("failed to create MirrorSubdir");
+        let op = SyncBuilder::new()
+                 .add_directory_pair(src_dir.clone(), src_dir.join("MirrorSubdir"))
+                 .sync();
+        while !op.is_done() {
+            thread::sleep(Duration::from_millis(100));
+        }
+        let mut problems = vec![];
+        while let Some(entry) = op.read_log() {
+            if let SyncEvent::PairRejected { problem, .. } = entry.event {
+                problems.push(problem);
+            }
+        }
+        assert_eq!(problems, &[RootProblem::Nested]);
+
+        let _ = fs::remove_dir_all(&src_dir).expect("failed to delete SyncBuilderRootTestsSource");
+        let _ = fs::remove_dir_all(&dest_dir).expect("failed to delete SyncBuilderRootTestsDest");
+    }
+
+    #[test]
+    fn test_panic_reported() {
+        let temp_dir = env::temp_dir();
+        let src_dir = temp_dir.join("SyncBuilderPanicTestsSource");
+        let _ = fs::remove_dir_all(&src_dir);
+        fs::create_dir(&src_dir).expect("failed to create SyncBuilderPanicTestsSource");
+        let dest_dir = temp_dir.join("SyncBuilderPanicTestsDest");
+        let _ = fs::remove_dir_all(&dest_dir);
+        fs::create_dir(&dest_dir).expect("failed to create SyncBuilderPanicTestsDest");
+
+        fs::create_dir(src_dir.join("bad")).expect("failed to create bad");
+        write_file(src_dir.join("bad/banana.txt"), b"b").expect("failed to create banana.txt");
+        fs::create_dir(src_dir.join("good")).expect("failed to create good");
+        write_file(src_dir.join("good/apple.txt"), b"a").expect("failed to create apple.txt");
+        // The destination has a file where the source has a directory.
+        write_file(dest_dir.join("good"), b"g").expect("failed to create good");
+
+        let op = SyncBuilder::new()
+                 .add_directory_pair(src_dir.clone(), dest_dir.clone())
+                 .filter(|path, _| {
+                     if path.ends_with("bad/banana.txt") {
+                         panic!("bad file");
+                     }
+                     true
+                 })
+                 .sync();
+        while !op.is_done() {
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        let mut errors = vec![];
+        while let Some(entry) = op.read_log() {
+            if let SyncEvent::Error { path, op, description, .. } = entry.event {
+                errors.push((path, op, description));
+            }
+        }
+        assert_eq!(errors, &[
+            (src_dir.join("bad"), SyncErrorOp::Sync, "internal error: bad file".to_string()),
+        ]);
+        assert_eq!(op.progress().errors, 1);
+        let dest_list = list_dir(dest_dir.join("good")).expect("failed to list dir");
+        assert_eq!(dest_list, &[
+            "F:apple.txt:a",
+        ]);
+
+        let _ = fs::remove_dir_all(&src_dir).expect("failed to delete SyncBuilderPanicTestsSource");
+        let _ = fs::remove_dir_all(&dest_dir).expect("failed to delete SyncBuilderPanicTestsDest");
+    }
+
+    #[test]
+    fn test_report() {
+        let temp_dir = env::temp_dir();
+        let src_dir = temp_dir.join("SyncBuilderReportTestsSource");
+        let _ = fs::remove_dir_all(&src_dir);
+        fs::create_dir(&src_dir).expect("failed to create SyncBuilderReportTestsSource");
+        let dest_dir = temp_dir.join("SyncBuilderReportTestsDest");
+        let _ = fs::remove_dir_all(&dest_dir);
+        fs::create_dir(&dest_dir).expect("failed to create SyncBuilderReportTestsDest");
+        let missing_dir = temp_dir.join("SyncBuilderReportTestsMissing");
+        let _ = fs::remove_dir_all(&missing_dir);
+
+        write_file(src_dir.join("banana.txt"), b"cd").expect("failed to create banana.txt");
+        write_file(src_dir.join("grape.txt"), b"efg").expect("failed to create grape.txt");
+        write_file(dest_dir.join("apple.txt"), b"bc").expect("failed to create apple.txt");
+
+        let op = SyncBuilder::new()
+                 .add_directory_pair(src_dir.clone(), dest_dir.clone())
+                 .add_directory_pair(missing_dir.clone(), dest_dir.join("Missing"))
+                 .sync();
+        let report = op.wait();
+        assert!(op.is_done());
+        assert!(!report.cancelled);
+        assert_eq!(report.files_copied, 2);
+        assert_eq!(report.bytes_copied, 5);
+        assert_eq!(report.files_deleted, 1);
+        assert_eq!(report.errors, 1);
+        assert_eq!(report.failures, &[
+            SyncFailure {
+                path: missing_dir.clone(),
+                op: SyncErrorOp::SyncPair,
+                kind: io::ErrorKind::NotFound,
+                description: RootProblem::Missing(missing_dir.clone()).to_string(),
+            },
+        ]);
+        assert_eq!(report.pairs, &[
+            PairReport {
+                src: src_dir.clone(),
+                dest: dest_dir.clone(),
+                rejected: None,
+                files_copied: 2,
+                bytes_copied: 5,
+                files_deleted: 1,
+                dirs_deleted: 0,
+                files_archived: 0,
+                errors: 0,
+            },
+            PairReport {
+                src: missing_dir.clone(),
+                dest: dest_dir.join("Missing"),
+                rejected: Some(RootProblem::Missing(missing_dir.clone())),
+                files_copied: 0,
+                bytes_copied: 0,
+                files_deleted: 0,
+                dirs_deleted: 0,
+                files_archived: 0,
+                errors: 1,
+            },
+        ]);
+        assert_eq!(op.report().map(|report| report.duration), Some(report.duration));
+
+        let _ = fs::remove_dir_all(&src_dir).expect("failed to delete SyncBuilderReportTestsSource");
+        let _ = fs::remove_dir_all(&dest_dir).expect("failed to delete SyncBuilderReportTestsDest");
+    }
+
+    #[test]
+    fn test_wait_and_callbacks() {
+        let temp_dir = env::temp_dir();
+        let src_dir = temp_dir.join("SyncBuilderWaitTestsSource");
+        let _ = fs::remove_dir_all(&src_dir);
+        fs::create_dir(&src_dir).expect("failed to create SyncBuilderWaitTestsSource");
+        let dest_dir = temp_dir.join("SyncBuilderWaitTestsDest");
+        let _ = fs::remove_dir_all(&dest_dir);
+        fs::create_dir(&dest_dir).expect("failed to create SyncBuilderWaitTestsDest");
+
+        write_file(src_dir.join("banana.txt"), b"cd").expect("failed to create banana.txt");
+
+        let op = SyncBuilder::new().add_directory_pair(src_dir.clone(), dest_dir.clone()).sync();
+        let (report_sender, report_receiver) = mpsc::channel();
+        op.on_complete(move |report| report_sender.send(report.files_copied).unwrap());
+        // Entries logged before the receiver is created are still received.
+        let log_receiver = op.log_receiver();
+
+        let copied: Vec<_> = log_receiver.iter().filter_map(|entry| match entry.event {
+            SyncEvent::CopyFinished { src, .. } => Some(src),
+            _ => None,
+        }).collect();
+        assert_eq!(copied, &[src_dir.join("banana.txt")]);
+        assert_eq!(report_receiver.recv_timeout(Duration::from_secs(10)), Ok(1));
+        assert_eq!(op.wait_timeout(Duration::from_millis(100)).map(|r| r.files_copied), Some(1));
+
+        // A callback registered after the operation is done is called right away.
+        let (report_sender, report_receiver) = mpsc::channel();
+        op.on_complete(move |report| report_sender.send(report.files_copied).unwrap());
+        assert_eq!(report_receiver.try_recv(), Ok(1));
+        assert!(op.log_receiver().try_recv().is_err());
+
+        let _ = fs::remove_dir_all(&src_dir).expect("failed to delete SyncBuilderWaitTestsSource");
+        let _ = fs::remove_dir_all(&dest_dir).expect("failed to delete SyncBuilderWaitTestsDest");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_policy() {
+        use std::os::unix::fs::symlink;
+
+        let temp_dir = env::temp_dir();
+        let src_dir = temp_dir.join("SyncBuilderSymlinkTestsSource");
+        let _ = fs::remove_dir_all(&src_dir);
+        fs::create_dir(&src_dir).expect("failed to create SyncBuilderSymlinkTestsSource");
+        let dest_dir = temp_dir.join("SyncBuilderSymlinkTestsDest");
+        let _ = fs::remove_dir_all(&dest_dir);
+        fs::create_dir(&dest_dir).expect("failed to create SyncBuilderSymlinkTestsDest");
+
+        write_file(src_dir.join("a.txt"), b"a").expect("failed to create a.txt");
+        fs::create_dir(src_dir.join("dir")).expect("failed to create dir");
+        write_file(src_dir.join("dir/b.txt"), b"b").expect("failed to create dir/b.txt");
+        symlink("a.txt", src_dir.join("link_file")).expect("failed to create link_file");
+        symlink("dir", src_dir.join("link_dir")).expect("failed to create link_dir");
+        symlink("..", src_dir.join("dir/loop")).expect("failed to create dir/loop");
+        symlink("missing", src_dir.join("dangling")).expect("failed to create dangling");
+        write_file(dest_dir.join("link_file"), b"x").expect("failed to create link_file");
+        symlink("a.txt", dest_dir.join("stale_link")).expect("failed to create stale_link");
+
+        // Copying creates the symlinks with the same targets, replacing the file in the way.
+        let report = SyncBuilder::new()
+                     .add_directory_pair(src_dir.clone(), dest_dir.clone())
+                     .symlink_policy(SymlinkPolicy::Copy)
+                     .sync()
+                     .wait();
+        assert_eq!(report.errors, 0);
+        assert_eq!(fs::read_link(dest_dir.join("link_file")).ok(), Some("a.txt".into()));
+        assert_eq!(fs::read_link(dest_dir.join("link_dir")).ok(), Some("dir".into()));
+        assert_eq!(fs::read_link(dest_dir.join("dir/loop")).ok(), Some("..".into()));
+        assert_eq!(fs::read_link(dest_dir.join("dangling")).ok(), Some("missing".into()));
+        assert!(fs::symlink_metadata(dest_dir.join("stale_link")).is_err());
+
+        // The symlinks are up to date, so nothing is copied again.
+        let report = SyncBuilder::new()
+                     .add_directory_pair(src_dir.clone(), dest_dir.clone())
+                     .symlink_policy(SymlinkPolicy::Copy)
+                     .sync()
+                     .wait();
+        assert_eq!((report.files_copied, report.files_deleted), (0, 0));
+
+        // Following copies what the symlinks point to, except for the ones that loop.
+        let op = SyncBuilder::new()
+                 .add_directory_pair(src_dir.clone(), dest_dir.clone())
+                 .symlink_policy(SymlinkPolicy::Follow)
+                 .sync();
+        let report = op.wait();
+        assert_eq!(report.errors, 0);
+        let mut skipped = vec![];
+        while let Some(entry) = op.read_log() {
+            if let SyncEvent::Skipped { path, reason } = entry.event {
+                skipped.push((path.strip_prefix(&src_dir).unwrap().to_path_buf(), reason));
+            }
+        }
+        skipped.sort_by(|&(ref path1, _), &(ref path2, _)| path1.cmp(path2));
+        assert_eq!(skipped, &[
+            (Path::new("dangling").to_path_buf(), SkipReason::Symlink),
+            (Path::new("dir/loop").to_path_buf(), SkipReason::SymlinkLoop),
+            (Path::new("link_dir/loop").to_path_buf(), SkipReason::SymlinkLoop),
+        ]);
+        let dest_list = vec![
+            "F:a.txt:a",
+            "F:dangling:",
+            "D:dir:",
+            "D:link_dir:",
+            "F:link_file:a",
+        ];
+        assert_eq!(list_dir(&dest_dir).expect("failed to list dir"), dest_list);
+        assert_eq!(list_dir(dest_dir.join("link_dir")).expect("failed to list dir"), &[
+            "F:b.txt:b",
+        ]);
+        // The skipped symlinks copied before are kept.
+        assert_eq!(fs::read_link(dest_dir.join("dangling")).ok(), Some("missing".into()));
+        assert_eq!(fs::read_link(dest_dir.join("dir/loop")).ok(), Some("..".into()));
+
+        // Skipping keeps whatever is in the place of the symlinks.
+        let report = SyncBuilder::new()
+                     .add_directory_pair(src_dir.clone(), dest_dir.clone())
+                     .symlink_policy(SymlinkPolicy::Skip)
+                     .sync()
+                     .wait();
+        assert_eq!(report.errors, 0);
+        assert_eq!(report.files_deleted + report.dirs_deleted, 0);
+        assert_eq!(list_dir(&dest_dir).expect("failed to list dir"), dest_list);
+        assert_eq!(fs::read_link(dest_dir.join("dir/loop")).ok(), Some("..".into()));
+
+        let _ = fs::remove_dir_all(&src_dir).expect("failed to delete SyncBuilderSymlinkTestsSource");
+        let _ = fs::remove_dir_all(&dest_dir).expect("failed to delete SyncBuilderSymlinkTestsDest");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_permissions() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+        use libc;
+        use permissions;
+
+        let temp_dir = env::temp_dir();
+        let src_dir = temp_dir.join("SyncBuilderPermissionsTestsSource");
+        let _ = fs::remove_dir_all(&src_dir);
+        fs::create_dir(&src_dir).expect("failed to create SyncBuilderPermissionsTestsSource");
+        let dest_dir = temp_dir.join("SyncBuilderPermissionsTestsDest");
+        let _ = fs::remove_dir_all(&dest_dir);
+        fs::create_dir(&dest_dir).expect("failed to create SyncBuilderPermissionsTestsDest");
+
+        let set_mode = |path: &Path, mode: u32| {
+            fs::set_permissions(path, fs::Permissions::from_mode(mode))
+                .expect("failed to set permissions");
+        };
+        let mode = |path: &Path| {
+            fs::metadata(path).expect("failed to read metadata").mode() & 0o7777
+        };
+        write_file(src_dir.join("run.sh"), b"s").expect("failed to create run.sh");
+        set_mode(&src_dir.join("run.sh"), 0o755);
+        write_file(src_dir.join("key"), b"k").expect("failed to create key");
+        set_mode(&src_dir.join("key"), 0o600);
+        fs::create_dir(src_dir.join("ro")).expect("failed to create ro");
+        write_file(src_dir.join("ro/a.txt"), b"a").expect("failed to create ro/a.txt");
+        set_mode(&src_dir.join("ro"), 0o555);
+        write_file(dest_dir.join("key"), b"k").expect("failed to create key");
+        set_mode(&dest_dir.join("key"), 0o644);
+        let owned = permissions::can_set_owner();
+        if owned {
+            let path = ::std::ffi::CString::new(src_dir.join("key").to_str().unwrap()).unwrap();
+            assert_eq!(unsafe { libc::chown(path.as_ptr(), 1234, 1234) }, 0);
+        }
+
+        let op = SyncBuilder::new()
+                 .add_directory_pair(src_dir.clone(), dest_dir.clone())
+                 .copy_permissions(true)
+                 .copy_ownership(true)
+                 .sync();
+        let report = op.wait();
+        assert_eq!(report.errors, 0);
+        // Only the permissions of the key were different.
+        let mut events = vec![];
+        while let Some(entry) = op.read_log() {
+            match entry.event {
+                SyncEvent::CopyFinished { dest, .. } => events.push(("copied", dest)),
+                SyncEvent::PermissionsCopied { path } => events.push(("permissions", path)),
+                _ => {},
+            }
+        }
+        events.sort();
+        assert_eq!(events, &[
+            ("copied", dest_dir.join("ro/a.txt")),
+            ("copied", dest_dir.join("run.sh")),
+            ("permissions", dest_dir.join("key")),
+            ("permissions", dest_dir.join("ro")),
+        ]);
+        assert_eq!(mode(&dest_dir.join("run.sh")), 0o755);
+        assert_eq!(mode(&dest_dir.join("key")), 0o600);
+        assert_eq!(mode(&dest_dir.join("ro")), 0o555);
+        if owned {
+            assert_eq!(fs::metadata(dest_dir.join("key")).expect("failed to read metadata").uid(),
+                       1234);
+        }
+
+        // The read-only directory in the destination can still be synced into, and it's read-only
+        // again afterward.
+        set_mode(&src_dir.join("ro"), 0o755);
+        write_file(src_dir.join("ro/a.txt"), b"ab").expect("failed to create ro/a.txt");
+        set_mode(&src_dir.join("ro"), 0o555);
+        let op = SyncBuilder::new()
+                 .add_directory_pair(src_dir.clone(), dest_dir.clone())
+                 .copy_permissions(true)
+                 .sync();
+        let report = op.wait();
+        assert_eq!(report.errors, 0);
+        assert_eq!(report.files_copied, 1);
+        assert_eq!(read_file(dest_dir.join("ro/a.txt")).expect("failed to read ro/a.txt"), b"ab");
+        assert_eq!(mode(&dest_dir.join("ro")), 0o555);
+        // Restoring the mode isn't a change.
+        while let Some(entry) = op.read_log() {
+            if let SyncEvent::PermissionsCopied { path } = entry.event {
+                panic!("permissions of {} copied", path.display());
+            }
+        }
+
+        set_mode(&src_dir.join("ro"), 0o755);
+        set_mode(&dest_dir.join("ro"), 0o755);
+        let _ = fs::remove_dir_all(&src_dir).expect("failed to delete SyncBuilderPermissionsTestsSource");
+        let _ = fs::remove_dir_all(&dest_dir).expect("failed to delete SyncBuilderPermissionsTestsDest");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_xattrs() {
+        use xattrs;
+
+        let temp_dir = env::temp_dir();
+        let src_dir = temp_dir.join("SyncBuilderXattrsTestsSource");
+        let _ = fs::remove_dir_all(&src_dir);
+        fs::create_dir(&src_dir).expect("failed to create SyncBuilderXattrsTestsSource");
+        let dest_dir = temp_dir.join("SyncBuilderXattrsTestsDest");
+        let _ = fs::remove_dir_all(&dest_dir);
+        fs::create_dir(&dest_dir).expect("failed to create SyncBuilderXattrsTestsDest");
+
+        let attr = |name: &str, value: &[u8]| (OsStr::new(name).to_os_string(), value.to_vec());
+        write_file(src_dir.join("a.txt"), b"a").expect("failed to create a.txt");
+        if xattrs::write(src_dir.join("a.txt"), &[attr("user.tag", b"1")]).is_err() {
+            // The temporary directory's file system doesn't support user attributes.
+            let _ = fs::remove_dir_all(&src_dir);
+            let _ = fs::remove_dir_all(&dest_dir);
+            return;
+        }
+        write_file(src_dir.join("b.txt"), b"b").expect("failed to create b.txt");
+        xattrs::write(src_dir.join("b.txt"), &[attr("user.tag", b"2")])
+            .expect("failed to set attributes of b.txt");
+        fs::create_dir(src_dir.join("dir")).expect("failed to create dir");
+        xattrs::write(src_dir.join("dir"), &[attr("user.tag", b"3")])
+            .expect("failed to set attributes of dir");
+        write_file(dest_dir.join("a.txt"), b"a").expect("failed to create a.txt");
+        xattrs::write(dest_dir.join("a.txt"), &[attr("user.old", b"x")])
+            .expect("failed to set attributes of a.txt");
+
+        let op = SyncBuilder::new()
+                 .add_directory_pair(src_dir.clone(), dest_dir.clone())
+                 .copy_xattrs(true)
+                 .sync();
+        let report = op.wait();
+        assert_eq!(report.errors, 0);
+        let mut events = vec![];
+        while let Some(entry) = op.read_log() {
+            match entry.event {
+                SyncEvent::CopyFinished { dest, .. } => events.push(("copied", dest)),
+                SyncEvent::XattrsCopied { path } => events.push(("xattrs", path)),
+                _ => {},
+            }
+        }
+        events.sort();
+        assert_eq!(events, &[
+            ("copied", dest_dir.join("b.txt")),
+            ("xattrs", dest_dir.join("a.txt")),
+            ("xattrs", dest_dir.join("dir")),
+        ]);
+        let read = |path: &Path| xattrs::read(path).expect("failed to read attributes");
+        assert_eq!(read(&dest_dir.join("a.txt")), &[attr("user.tag", b"1")]);
+        assert_eq!(read(&dest_dir.join("b.txt")), &[attr("user.tag", b"2")]);
+        assert_eq!(read(&dest_dir.join("dir")), &[attr("user.tag", b"3")]);
+
+        // Everything is up to date now.
+        let op = SyncBuilder::new()
+                 .add_directory_pair(src_dir.clone(), dest_dir.clone())
+                 .copy_xattrs(true)
+                 .plan();
+        op.wait();
+        assert_eq!(op.read_plan(), None);
+
+        let _ = fs::remove_dir_all(&src_dir).expect("failed to delete SyncBuilderXattrsTestsSource");
+        let _ = fs::remove_dir_all(&dest_dir).expect("failed to delete SyncBuilderXattrsTestsDest");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_hard_links() {
+        use std::os::unix::fs::MetadataExt;
+
+        let temp_dir = env::temp_dir();
+        let src_dir = temp_dir.join("SyncBuilderHardLinkTestsSource");
+        let _ = fs::remove_dir_all(&src_dir);
+        fs::create_dir(&src_dir).expect("failed to create SyncBuilderHardLinkTestsSource");
+        let src_dir2 = temp_dir.join("SyncBuilderHardLinkTestsSource2");
+        let _ = fs::remove_dir_all(&src_dir2);
+        fs::create_dir(&src_dir2).expect("failed to create SyncBuilderHardLinkTestsSource2");
+        let dest_dir = temp_dir.join("SyncBuilderHardLinkTestsDest");
+        let _ = fs::remove_dir_all(&dest_dir);
+        fs::create_dir(&dest_dir).expect("failed to create SyncBuilderHardLinkTestsDest");
+        let dest_dir2 = temp_dir.join("SyncBuilderHardLinkTestsDest2");
+        let _ = fs::remove_dir_all(&dest_dir2);
+        fs::create_dir(&dest_dir2).expect("failed to create SyncBuilderHardLinkTestsDest2");
+
+        write_file(src_dir.join("a.txt"), b"a").expect("failed to create a.txt");
+        fs::hard_link(src_dir.join("a.txt"), src_dir.join("b.txt")).expect("failed to link b.txt");
+        fs::create_dir(src_dir.join("dir")).expect("failed to create dir");
+        fs::hard_link(src_dir.join("a.txt"), src_dir.join("dir/c.txt"))
+            .expect("failed to link dir/c.txt");
+        fs::hard_link(src_dir.join("a.txt"), src_dir2.join("d.txt")).expect("failed to link d.txt");
+        write_file(src_dir.join("e.txt"), b"e").expect("failed to create e.txt");
+        // A separate copy in the destination is replaced by a link.
+        write_file(dest_dir.join("b.txt"), b"a").expect("failed to create b.txt");
+
+        let sync = || {
+            SyncBuilder::new()
+                .add_directory_pair(src_dir.clone(), dest_dir.clone())
+                .add_directory_pair(src_dir2.clone(), dest_dir2.clone())
+                .preserve_hard_links(true)
+                .sync()
+                .wait()
+        };
+        let report = sync();
+        assert_eq!(report.errors, 0);
+        let inode = |path: &Path| fs::metadata(path).expect("failed to read metadata").ino();
+        let linked = [dest_dir.join("b.txt"), dest_dir.join("dir/c.txt"), dest_dir2.join("d.txt")];
+        for path in &linked {
+            assert_eq!(inode(path), inode(&dest_dir.join("a.txt")));
+            assert_eq!(read_file(path).expect("failed to read file"), b"a");
+        }
+        assert_eq!(fs::metadata(dest_dir.join("e.txt")).expect("failed to read metadata").nlink(), 1);
+
+        // The links are up to date, so nothing is copied or linked again.
+        let report = sync();
+        assert_eq!(report.errors, 0);
+        assert_eq!(report.files_copied, 0);
+
+        let _ = fs::remove_dir_all(&src_dir).expect("failed to delete SyncBuilderHardLinkTestsSource");
+        let _ = fs::remove_dir_all(&src_dir2).expect("failed to delete SyncBuilderHardLinkTestsSource2");
+        let _ = fs::remove_dir_all(&dest_dir).expect("failed to delete SyncBuilderHardLinkTestsDest");
+        let _ = fs::remove_dir_all(&dest_dir2).expect("failed to delete SyncBuilderHardLinkTestsDest2");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_sparse_files() {
+        use std::io::{Seek, SeekFrom};
+        use std::os::unix::fs::MetadataExt;
+
+        let temp_dir = env::temp_dir();
+        let src_dir = temp_dir.join("SyncBuilderSparseTestsSource");
+        let _ = fs::remove_dir_all(&src_dir);
+        fs::create_dir(&src_dir).expect("failed to create SyncBuilderSparseTestsSource");
+        let dest_dir = temp_dir.join("SyncBuilderSparseTestsDest");
+        let _ = fs::remove_dir_all(&dest_dir);
+        fs::create_dir(&dest_dir).expect("failed to create SyncBuilderSparseTestsDest");
+
+        // The file has data at the start and in the middle, with holes between and at the end.
+        const LEN: u64 = 16 * 1024 * 1024;
+        {
+            let mut file = File::create(src_dir.join("disk.img"))
+                .expect("failed to create disk.img");
+            file.write_all(b"start").expect("failed to write disk.img");
+            file.seek(SeekFrom::Start(LEN / 2)).expect("failed to seek disk.img");
+            file.write_all(b"middle").expect("failed to write disk.img");
+            file.set_len(LEN).expect("failed to write disk.img");
+        }
+        write_file(src_dir.join("empty.txt"), b"").expect("failed to create empty.txt");
+
+        let report = SyncBuilder::new()
+            .add_directory_pair(src_dir.clone(), dest_dir.clone())
+            .sync()
+            .wait();
+        assert_eq!(report.errors, 0);
+        assert_eq!(report.files_copied, 2);
+        let src_contents = read_file(src_dir.join("disk.img")).expect("failed to read disk.img");
+        let dest_contents = read_file(dest_dir.join("disk.img")).expect("failed to read disk.img");
+        assert_eq!(dest_contents.len() as u64, LEN);
+        assert!(dest_contents == src_contents);
+        assert_eq!(report.bytes_copied + report.sparse_bytes_skipped, LEN);
+        // Only check the holes if the file system made the source file sparse.
+        let blocks = |path: &Path| fs::metadata(path).expect("failed to read metadata").blocks();
+        if blocks(&src_dir.join("disk.img")) * 512 < LEN / 2 {
+            assert!(report.sparse_bytes_skipped > 0);
+            assert!(blocks(&dest_dir.join("disk.img")) * 512 < LEN / 2);
+        }
+        assert_eq!(read_file(dest_dir.join("empty.txt")).expect("failed to read empty.txt"), b"");
+
+        fs::remove_dir_all(&src_dir).expect("failed to delete SyncBuilderSparseTestsSource");
+        fs::remove_dir_all(&dest_dir).expect("failed to delete SyncBuilderSparseTestsDest");
+    }
 }
@@ -0,0 +1,58 @@
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use libc::{self, c_long, time_t, timespec};
+
+/// Sets the created time of the specified file.
+///
+/// Unix file systems don't provide a way to change the created (birth) time of a file, so this
+/// does nothing.
+pub fn set_created<P: AsRef<Path>>(_file: P, _time: SystemTime) -> Result<(), io::Error> {
+    Ok(())
+}
+
+/// Returns true if `set_created()` can change the created time, which it can't on Unix.
+pub fn can_set_created() -> bool {
+    false
+}
+
+/// Sets the last modified time of the specified file.
+pub fn set_modified<P: AsRef<Path>>(file: P, time: SystemTime) -> Result<(), io::Error> {
+    let path = CString::new(file.as_ref().as_os_str().as_bytes())?;
+    // Leave the access time alone.
+    let times = [
+        timespec { tv_sec: 0, tv_nsec: libc::UTIME_OMIT },
+        system_time_to_timespec(time),
+    ];
+    unsafe {
+        if libc::utimensat(libc::AT_FDCWD, path.as_ptr(), times.as_ptr(), 0) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+fn system_time_to_timespec(time: SystemTime) -> timespec {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(dur) => timespec {
+            tv_sec: dur.as_secs() as time_t,
+            tv_nsec: dur.subsec_nanos() as c_long,
+        },
+        Err(err) => {
+            // The time is before 1970, so the seconds are negative, but tv_nsec must still be
+            // positive.
+            let dur = err.duration();
+            if dur.subsec_nanos() == 0 {
+                timespec { tv_sec: -(dur.as_secs() as time_t), tv_nsec: 0 }
+            } else {
+                timespec {
+                    tv_sec: -(dur.as_secs() as time_t) - 1,
+                    tv_nsec: (1_000_000_000 - dur.subsec_nanos()) as c_long,
+                }
+            }
+        },
+    }
+}
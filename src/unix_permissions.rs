@@ -0,0 +1,55 @@
+use std::ffi::CString;
+use std::fs::{self, Metadata, Permissions};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+
+use libc;
+
+// The permission bits of a mode, including setuid, setgid, and sticky, without the file type.
+const MODE_MASK: u32 = 0o7777;
+
+/// Returns true if the files have the same permission bits.
+pub fn mode_equal(meta1: &Metadata, meta2: &Metadata) -> bool {
+    meta1.mode() & MODE_MASK == meta2.mode() & MODE_MASK
+}
+
+/// Sets the permission bits of the specified file to those in `meta`.
+pub fn set_mode<P: AsRef<Path>>(file: P, meta: &Metadata) -> Result<(), io::Error> {
+    fs::set_permissions(file, Permissions::from_mode(meta.mode() & MODE_MASK))
+}
+
+/// Gives the owner of the specified directory permission to list and change its contents if it
+/// doesn't have it. Returns true if the mode was changed, so that it can be restored afterward.
+pub fn make_writable<P: AsRef<Path>>(dir: P, meta: &Metadata) -> Result<bool, io::Error> {
+    let mode = meta.mode() & MODE_MASK;
+    if mode & 0o700 == 0o700 {
+        return Ok(false);
+    }
+    fs::set_permissions(dir, Permissions::from_mode(mode | 0o700))?;
+    Ok(true)
+}
+
+/// Returns true if the files have the same owner and group.
+pub fn owner_equal(meta1: &Metadata, meta2: &Metadata) -> bool {
+    meta1.uid() == meta2.uid() && meta1.gid() == meta2.gid()
+}
+
+/// Sets the owner and group of the specified file to those in `meta`. Changing the mode afterward
+/// is necessary to restore setuid and setgid bits, which are cleared when the owner is changed.
+pub fn set_owner<P: AsRef<Path>>(file: P, meta: &Metadata) -> Result<(), io::Error> {
+    let path = CString::new(file.as_ref().as_os_str().as_bytes())?;
+    unsafe {
+        if libc::chown(path.as_ptr(), meta.uid(), meta.gid()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Returns true if the process is allowed to give files to other users, which requires running as
+/// root.
+pub fn can_set_owner() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
@@ -1,10 +1,11 @@
 
-use std::fs::File;
+use std::fs::{File, OpenOptions};
 use std::io;
 use std::mem;
 use std::path::Path;
 use std::ptr;
 use std::time::{Duration, SystemTime};
+use std::os::windows::fs::OpenOptionsExt;
 use std::os::windows::io::AsRawHandle;
 
 use kernel32::*;
@@ -13,7 +14,7 @@ use winapi::*;
 /// Sets the created time of the specified file.
 pub fn set_created<P: AsRef<Path>>(file: P, time: SystemTime) -> Result<(), io::Error> {
     unsafe {
-        let file = File::open(file)?;
+        let file = open_for_set_time(file)?;
         let file_time = system_time_to_filetime(time)?;
         if SetFileTime(file.as_raw_handle(), &file_time as *const FILETIME, ptr::null(), ptr::null()) == 0 {
             return Err(io::Error::last_os_error());
@@ -22,10 +23,15 @@ pub fn set_created<P: AsRef<Path>>(file: P, time: SystemTime) -> Result<(), io::
     }
 }
 
+/// Returns true if `set_created()` can change the created time.
+pub fn can_set_created() -> bool {
+    true
+}
+
 /// Sets the last modified time of the specified file.
 pub fn set_modified<P: AsRef<Path>>(file: P, time: SystemTime) -> Result<(), io::Error> {
     unsafe {
-        let file = File::open(file)?;
+        let file = open_for_set_time(file)?;
         let file_time = system_time_to_filetime(time)?;
         if SetFileTime(file.as_raw_handle(), ptr::null(), ptr::null(), &file_time as *const FILETIME) == 0 {
             return Err(io::Error::last_os_error());
@@ -34,6 +40,11 @@ pub fn set_modified<P: AsRef<Path>>(file: P, time: SystemTime) -> Result<(), io:
     }
 }
 
+// SetFileTime needs a handle with FILE_WRITE_ATTRIBUTES access, which File::open doesn't request.
+fn open_for_set_time<P: AsRef<Path>>(file: P) -> Result<File, io::Error> {
+    OpenOptions::new().access_mode(FILE_WRITE_ATTRIBUTES).open(file)
+}
+
 fn duration_to_intervals(duration: Duration) -> u64 {
     duration.as_secs() * 10_000_000 + (duration.subsec_nanos() / 100) as u64
 }
@@ -0,0 +1,39 @@
+use std::fs::Metadata;
+use std::io;
+use std::path::Path;
+
+// Windows permissions are ACLs, which aren't copied, so all of these do nothing. The read-only
+// attribute isn't copied either because a read-only file can't be replaced by a newer copy.
+
+/// Returns true if the files have the same permission bits, which is always on Windows.
+pub fn mode_equal(_meta1: &Metadata, _meta2: &Metadata) -> bool {
+    true
+}
+
+/// Sets the permission bits of the specified file to those in `meta`, which does nothing on
+/// Windows.
+pub fn set_mode<P: AsRef<Path>>(_file: P, _meta: &Metadata) -> Result<(), io::Error> {
+    Ok(())
+}
+
+/// Gives the owner of the specified directory permission to change its contents, which does
+/// nothing on Windows.
+pub fn make_writable<P: AsRef<Path>>(_dir: P, _meta: &Metadata) -> Result<bool, io::Error> {
+    Ok(false)
+}
+
+/// Returns true if the files have the same owner and group, which is always on Windows.
+pub fn owner_equal(_meta1: &Metadata, _meta2: &Metadata) -> bool {
+    true
+}
+
+/// Sets the owner and group of the specified file to those in `meta`, which does nothing on
+/// Windows.
+pub fn set_owner<P: AsRef<Path>>(_file: P, _meta: &Metadata) -> Result<(), io::Error> {
+    Ok(())
+}
+
+/// Returns true if the process is allowed to give files to other users, which is never on Windows.
+pub fn can_set_owner() -> bool {
+    false
+}